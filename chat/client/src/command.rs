@@ -0,0 +1,139 @@
+use protocol::{Frame, ModAction, Status};
+
+pub const USAGE: &str = "/nick <name>, /login <nick> <password>, /register <nick> <password>, \
+     /join #room, /leave, /list, /msg <nick> <text>, /history <n>, /away, /back";
+// 房间或 * (全服) 可省略，默认当前房间；时长如 30s、10m、2h、7d，省略为永久
+pub const MOD_USAGE: &str = "/kick /ban /unban /mute /unmute /op /deop [#room|*] <nick|ip> \
+     [duration] [reason], /bans [#room|*]";
+
+// 斜杠开头的是命令，其余作为聊天消息发到当前房间
+pub fn parse_command(msg: &str) -> Result<Frame, &'static str> {
+    if !msg.starts_with('/') {
+        return Ok(Frame::Chat {
+            room: String::new(),
+            from: String::new(),
+            timestamp: 0,
+            text: msg.to_string(),
+        });
+    }
+
+    let (command, arg) = match msg.split_once(' ') {
+        Some((command, arg)) => (command, arg.trim()),
+        None => (msg, ""),
+    };
+    match command {
+        "/nick" if !arg.is_empty() => Ok(Frame::Nick {
+            nick: arg.to_string(),
+        }),
+        "/nick" => Err("usage: /nick <name>"),
+        "/join" if !arg.is_empty() => Ok(Frame::Join {
+            room: arg.to_string(),
+            who: String::new(),
+        }),
+        "/join" => Err("usage: /join #room"),
+        "/leave" => Ok(Frame::Leave {
+            room: String::new(),
+            who: String::new(),
+        }),
+        "/login" | "/register" => match arg.split_once(' ') {
+            Some((nick, password)) if !password.trim().is_empty() => {
+                let nick = nick.to_string();
+                let password = password.trim().to_string();
+                Ok(match command {
+                    "/login" => Frame::Login { nick, password },
+                    _ => Frame::Register { nick, password },
+                })
+            }
+            _ if command == "/login" => Err("usage: /login <nick> <password>"),
+            _ => Err("usage: /register <nick> <password>"),
+        },
+        "/list" => Ok(Frame::List),
+        "/away" | "/back" => Ok(Frame::Presence {
+            who: String::new(),
+            status: match command {
+                "/away" => Status::Away,
+                _ => Status::Online,
+            },
+        }),
+        "/history" => match arg.parse() {
+            Ok(count) => Ok(Frame::History { count }),
+            Err(_) => Err("usage: /history <n>"),
+        },
+        "/msg" => match arg.split_once(' ') {
+            Some((to, text)) if !text.trim().is_empty() => Ok(Frame::Private {
+                to: to.to_string(),
+                from: String::new(),
+                timestamp: 0,
+                text: text.trim().to_string(),
+            }),
+            _ => Err("usage: /msg <nick> <text>"),
+        },
+        "/kick" | "/ban" | "/unban" | "/mute" | "/unmute" | "/op" | "/deop" | "/bans" => {
+            parse_moderation(command, arg)
+        }
+        _ => Err("unknown command"),
+    }
+}
+
+fn parse_moderation(command: &str, arg: &str) -> Result<Frame, &'static str> {
+    let (action, usage) = match command {
+        "/kick" => (ModAction::Kick, "usage: /kick [#room|*] <nick> [reason]"),
+        "/ban" => (
+            ModAction::Ban,
+            "usage: /ban [#room|*] <nick|ip> [duration] [reason]",
+        ),
+        "/unban" => (ModAction::Unban, "usage: /unban [#room|*] <nick|ip>"),
+        "/mute" => (
+            ModAction::Mute,
+            "usage: /mute [#room] <nick> [duration] [reason]",
+        ),
+        "/unmute" => (ModAction::Unmute, "usage: /unmute [#room] <nick>"),
+        "/op" => (ModAction::Op, "usage: /op [#room] <nick>"),
+        "/deop" => (ModAction::Deop, "usage: /deop [#room] <nick>"),
+        _ => (ModAction::Bans, "usage: /bans [#room|*]"),
+    };
+
+    let mut words = arg.split_whitespace().peekable();
+    let room = match words.peek() {
+        Some(word) if *word == "*" || word.starts_with('#') => words.next().unwrap().to_string(),
+        _ => String::new(),
+    };
+    let target = match (action, words.next()) {
+        (ModAction::Bans, None) => String::new(),
+        (ModAction::Bans, Some(_)) | (_, None) => return Err(usage),
+        (_, Some(target)) => target.to_string(),
+    };
+    let mut duration = 0;
+    if matches!(action, ModAction::Ban | ModAction::Mute)
+        && let Some(secs) = words.peek().and_then(|word| parse_duration(word))
+    {
+        duration = secs;
+        words.next();
+    }
+    let reason = words.collect::<Vec<_>>().join(" ");
+
+    Ok(Frame::Moderate {
+        action,
+        room,
+        target,
+        duration,
+        reason,
+    })
+}
+
+// 30s、10m、2h、7d，纯数字为秒
+fn parse_duration(text: &str) -> Option<u64> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => text.split_at(at),
+        None => (text, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    let secs = number.parse::<u64>().ok()?.checked_mul(scale)?;
+    (secs > 0).then_some(secs)
+}
@@ -0,0 +1,159 @@
+// 行模式：逐行读取标准输入，收到的消息直接打印，适合脚本和管道
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use protocol::{Frame, ProtocolError, Status};
+
+use crate::command::{MOD_USAGE, USAGE, parse_command};
+use crate::session::Session;
+use crate::transfer::{self, Notice, Transfers};
+use crate::{Input, Settings, format_time};
+
+pub async fn run<S>(stream: S, hello: Frame, mut input: Input, settings: Settings)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let mut session = match Session::start(stream, hello, settings.heartbeat).await {
+        Ok(session) => session,
+        Err(e) => return println!("{}", e),
+    };
+    let (mut transfers, mut notices) = Transfers::new(settings.transfer, session.out.clone());
+
+    println!(
+        "write a message ({}, {}, {}, :quit to exit):",
+        USAGE,
+        transfer::USAGE,
+        MOD_USAGE
+    );
+    loop {
+        tokio::select! {
+            line = input.next_line() => {
+                let line = line.expect("read from stdin failed");
+                let Some(line) = line else { break };
+                let msg = line.trim();
+                if msg == ":quit" {
+                    break;
+                }
+                if msg.is_empty() {
+                    continue;
+                }
+                if Transfers::is_command(msg) {
+                    if let Err(e) = transfers.command(msg).await {
+                        println!("! {}", e);
+                    }
+                    continue;
+                }
+
+                match parse_command(msg) {
+                    Ok(frame) => {
+                        if !session.send(frame).await {
+                            break;
+                        }
+                    }
+                    Err(usage) => println!("! {}", usage),
+                }
+            }
+            frame = session.frames.recv() => match frame {
+                Some(Ok(frame)) => {
+                    if let Some(frame) = transfers.on_frame(frame).await {
+                        print_frame(&frame);
+                    }
+                }
+                Some(Err(e)) if e.is_recoverable() => println!("! bad frame from server: {}", e),
+                Some(Err(ProtocolError::Closed)) | None => {
+                    println!("connection with server was severed");
+                    std::process::exit(1);
+                }
+                Some(Err(e)) => {
+                    println!("connection with server was severed: {}", e);
+                    std::process::exit(1);
+                }
+            },
+            Some(notice) = notices.recv() => print_notice(&notice),
+        }
+    }
+    session.close().await;
+    println!("bye bye!")
+}
+
+fn print_notice(notice: &Notice) {
+    match &notice.peer {
+        Some(peer) => println!("* [file {}] {}", peer, notice.text),
+        None => println!("* {}", notice.text),
+    }
+}
+
+fn print_frame(frame: &Frame) {
+    match frame {
+        Frame::Chat {
+            room,
+            from,
+            timestamp,
+            text,
+        } => println!("[{}] {} {}: {}", format_time(*timestamp), room, from, text),
+        Frame::Join { room, who } => println!("* {} joined {}", who, room),
+        Frame::Leave { room, who } => println!("* {} left {}", who, room),
+        Frame::Error { message } => println!("! {}", message),
+        Frame::Welcome { nick } => println!("* you are now known as {}", nick),
+        Frame::Renamed { old, new } => println!("* {} is now known as {}", old, new),
+        Frame::Notice { text, .. } => println!("* {}", text),
+        Frame::Presence { who, status } => match status {
+            Status::Away => println!("* {} is away", who),
+            Status::Online => println!("* {} is back", who),
+        },
+        Frame::Rooms { rooms } if rooms.is_empty() => println!("* no rooms"),
+        Frame::Rooms { rooms } => {
+            for room in rooms {
+                // 管理员名字前加 @，暂时离开的后面标注
+                let members: Vec<String> = room
+                    .members
+                    .iter()
+                    .map(|member| {
+                        let mark = if room.ops.contains(member) { "@" } else { "" };
+                        match room.away.contains(member) {
+                            true => format!("{}{} (away)", mark, member),
+                            false => format!("{}{}", mark, member),
+                        }
+                    })
+                    .collect();
+                println!(
+                    "* {} ({}): {}",
+                    room.name,
+                    members.len(),
+                    members.join(", ")
+                );
+            }
+        }
+        Frame::Private {
+            to,
+            from,
+            timestamp,
+            text,
+        } => println!(
+            "[{}] *{} -> {}*: {}",
+            format_time(*timestamp),
+            from,
+            to,
+            text
+        ),
+        Frame::Nick { .. }
+        | Frame::List
+        | Frame::History { .. }
+        | Frame::Login { .. }
+        | Frame::Register { .. }
+        | Frame::FileOffer { .. }
+        | Frame::FileReply { .. }
+        | Frame::FileChunk { .. }
+        | Frame::FileCancel { .. }
+        | Frame::Moderate { .. }
+        | Frame::Ping { .. }
+        | Frame::Pong { .. }
+        // 服务器间链接的帧不会发给客户端
+        | Frame::Link { .. }
+        | Frame::Server { .. }
+        | Frame::Split { .. }
+        | Frame::User { .. }
+        | Frame::Quit { .. }
+        // 行模式按行读取输入，不显示也不发送正在输入提示
+        | Frame::Typing { .. } => {}
+    }
+}
@@ -1,61 +1,210 @@
-use std::io::{self, ErrorKind, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc;
+use clap::Parser;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
 use std::time::Duration;
-use std::{clone, thread};
-
-const LOCAL: &str = "127.0.0.1:6000";
-const MSG_SIZE: u8 = 32;
-
-fn main() {
-    let mut client = TcpStream::connect(LOCAL).expect("Listener failed to bind");
-    client
-        .set_nonblocking(true)
-        .expect("failed to initialize non_blocking");
-
-    let (tx, rx) = mpsc::channel::<String>();
-
-    thread::spawn(move || {
-        loop {
-            let mut buff = vec![0, MSG_SIZE];
-            match client.read_exact(&mut buff) {
-                Ok(_) => {
-                    let msg = buff.into_iter().take_while(|&x| x != 0).collect::<Vec<_>>();
-                    println!("message recv {:?}", msg);
-                }
-                Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-                Err(_) => {
-                    print!("connection with server was severed");
-                    break;
-                }
-            }
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, Lines, Stdin};
+use tokio::net::TcpStream;
+
+use chrono::{Local, TimeZone};
+use protocol::Frame;
+use rustls::pki_types::ServerName;
+use session::Heartbeat;
+
+mod command;
+mod line;
+mod session;
+mod tls;
+mod transfer;
+mod tui;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, args_override_self = true)]
+struct Args {
+    /// 配置文件 (TOML，键名与长参数相同)，命令行参数优先
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// 服务端地址 (IP 或主机名)
+    #[arg(short, long, default_value = "127.0.0.1")]
+    server: String,
+
+    /// 服务端端口
+    #[arg(short, long, default_value_t = 6000)]
+    port: u16,
+
+    /// 昵称，不指定时在终端询问
+    #[arg(short, long)]
+    nick: Option<String>,
+
+    /// 账号密码，与 nick 一起指定时直接登录注册账号
+    #[arg(long, requires = "nick")]
+    password: Option<String>,
+
+    /// 使用行模式而不是全屏界面，标准输入不是终端时自动启用
+    #[arg(long)]
+    plain: bool,
+
+    /// 接收文件的保存目录
+    #[arg(long, default_value = ".")]
+    download_dir: PathBuf,
+
+    /// 发送和接收的单个文件大小上限 (字节)，超过的请求自动拒绝
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    max_file_size: u64,
+
+    /// 心跳间隔 (秒)
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval: u64,
+
+    /// 超过这么多秒没收到服务端的任何消息就断开，须大于心跳间隔
+    #[arg(long, default_value_t = 90)]
+    ping_timeout: u64,
+
+    /// 全屏界面中不向房间发送正在输入提示
+    #[arg(long)]
+    no_typing: bool,
+
+    /// 全屏界面中无操作多少秒后自动标记为离开，0 表示不自动标记
+    #[arg(long, default_value_t = 300)]
+    away_after: u64,
+
+    /// 使用 TLS 连接服务端
+    #[arg(long)]
+    tls: bool,
+
+    /// 信任的 CA 证书 (PEM)，用于自签证书；默认使用内置根证书
+    #[arg(long, requires = "tls")]
+    tls_ca: Option<PathBuf>,
+
+    /// 固定服务端证书的 SHA-256 指纹，指定后不再校验证书链
+    #[arg(long, requires = "tls", conflicts_with = "tls_ca")]
+    tls_pin: Option<String>,
+
+    /// 校验证书时使用的服务端名称，默认为 server
+    #[arg(long, requires = "tls")]
+    tls_server_name: Option<String>,
+}
 
-            match rx.try_recv() {
-                Ok(msg)=>{
-                    let mut buff  = msg.clone().into_bytes();
-                    buff.resize(MSG_SIZE as usize, 0);
-                    client.write_all(&buff).expect("writing to socket failed");
-                    println!("message send {:?}",msg);
-                }
-                Err(_)=>{
-
-                }
-                
+pub type Input = Lines<BufReader<Stdin>>;
+
+/// 行模式和全屏界面共用的会话设置
+pub struct Settings {
+    pub transfer: transfer::Options,
+    pub heartbeat: Heartbeat,
+    /// 输入时向房间发送正在输入提示 (仅全屏界面)
+    pub typing: bool,
+    /// 无操作多久后自动标记为离开 (仅全屏界面)
+    pub away_after: Option<Duration>,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = match config_args::args() {
+        Ok(args) => Args::parse_from(args),
+        Err(e) => {
+            eprintln!("failed to load config: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if args.ping_timeout <= args.ping_interval {
+        eprintln!("--ping-timeout must be greater than --ping-interval");
+        std::process::exit(2);
+    }
+    let mut input = BufReader::new(tokio::io::stdin()).lines();
+
+    // IPv6 地址可能写成 [::1] 形式
+    let server = args
+        .server
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let socket = match TcpStream::connect((server.as_str(), args.port)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("failed to connect to {}:{}: {}", args.server, args.port, e);
+            return;
+        }
+    };
+
+    // 昵称可以作为参数传入，否则在终端询问
+    let nick = match args.nick {
+        Some(nick) => nick,
+        None => prompt_nick(&mut input).await,
+    };
+    let hello = match args.password {
+        Some(password) => Frame::Login { nick, password },
+        None => Frame::Nick { nick },
+    };
+
+    let settings = Settings {
+        transfer: transfer::Options {
+            dir: args.download_dir,
+            max_size: args.max_file_size,
+        },
+        heartbeat: Heartbeat {
+            interval: Duration::from_secs(args.ping_interval),
+            timeout: Duration::from_secs(args.ping_timeout),
+        },
+        typing: !args.no_typing,
+        away_after: (args.away_after > 0).then(|| Duration::from_secs(args.away_after)),
+    };
+
+    if !args.tls {
+        return run(socket, hello, input, args.plain, settings).await;
+    }
+
+    let connector = tls::connector(args.tls_ca.as_deref(), args.tls_pin.as_deref())
+        .expect("failed to load TLS config");
+    let host = args.tls_server_name.unwrap_or(server);
+    let name = ServerName::try_from(host).expect("invalid TLS server name");
+    match connector.connect(name, socket).await {
+        Ok(stream) => {
+            if let Some(cert) = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|c| c.first())
+            {
+                println!("* TLS certificate {}", tls::fingerprint(cert));
             }
-            thread::sleep(Duration::from_micros(100));
+            run(stream, hello, input, args.plain, settings).await
         }
-    });
+        Err(e) => println!("TLS handshake failed: {}", e),
+    }
+}
 
+async fn run<S>(stream: S, hello: Frame, input: Input, plain: bool, settings: Settings)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    // 标准输入输出不是终端时 (脚本、管道) 自动使用行模式
+    if plain || !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        line::run(stream, hello, input, settings).await
+    } else {
+        drop(input);
+        if let Err(e) = tui::run(stream, hello, settings).await {
+            println!("{}", e);
+        }
+    }
+}
 
-    println!("write a message:");
-    loop{
-        let mut buff = String::new ();
-        io::stdin().read_line(&mut buff).expect("read from stdin failed");
-        let msg = buff.trim().to_string();
+async fn prompt_nick(input: &mut Input) -> String {
+    loop {
+        print!("nickname: ");
+        io::stdout().flush().expect("flush stdout failed");
 
-        if msg == ":quit" || tx.send(msg).is_err() {
-            break;
+        match input.next_line().await.expect("read from stdin failed") {
+            Some(line) if !line.trim().is_empty() => return line.trim().to_string(),
+            Some(_) => {}
+            None => std::process::exit(0),
         }
     }
-    println!("bye bye!")
+}
+
+// 服务端时间戳转成本地时间显示
+pub fn format_time(timestamp: u64) -> String {
+    match Local.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) => time.format("%H:%M:%S").to_string(),
+        None => "--:--:--".to_string(),
+    }
 }
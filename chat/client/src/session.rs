@@ -0,0 +1,121 @@
+// 连接会话：读写各一个任务，界面和文件上传任务通过队列发送消息
+//
+// 心跳在会话内部处理：定时向服务端发送 Ping 并回复服务端的 Ping，
+// 超时没收到任何消息就当作连接已断开
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior, interval_at, timeout};
+
+use protocol::async_io::{read_frame, write_frame};
+use protocol::{Frame, ProtocolError};
+
+const QUEUE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    /// 发送心跳的间隔
+    pub interval: Duration,
+    /// 多久没收到服务端的任何消息就断开
+    pub timeout: Duration,
+}
+
+pub struct Session {
+    /// 收到的消息；连接断开后关闭
+    pub frames: mpsc::Receiver<Result<Frame, ProtocolError>>,
+    /// 要发送的消息
+    pub out: mpsc::Sender<Frame>,
+    close: oneshot::Sender<()>,
+    writer: JoinHandle<()>,
+}
+
+impl Session {
+    /// 发送登录消息并启动读写任务
+    pub async fn start<S>(stream: S, hello: Frame, heartbeat: Heartbeat) -> Result<Session, String>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        write_frame(&mut writer, &hello)
+            .await
+            .map_err(|e| format!("login failed: {}", e))?;
+
+        let (out, mut queue) = mpsc::channel(QUEUE_CAPACITY);
+        let (frame_tx, frames) = mpsc::channel(QUEUE_CAPACITY);
+        let errors = frame_tx.clone();
+        let pong = out.clone();
+        tokio::spawn(async move {
+            loop {
+                let frame = match timeout(heartbeat.timeout, read_frame(&mut reader)).await {
+                    Ok(Ok(Frame::Ping { token })) => {
+                        let _ = pong.send(Frame::Pong { token }).await;
+                        continue;
+                    }
+                    Ok(Ok(Frame::Pong { .. })) => continue,
+                    Ok(frame) => frame,
+                    Err(_) => Err(ProtocolError::Io(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "server not responding",
+                    ))),
+                };
+                let fatal = matches!(&frame, Err(e) if !e.is_recoverable());
+                if frame_tx.send(frame).await.is_err() || fatal {
+                    break;
+                }
+            }
+        });
+
+        let (close, mut closed) = oneshot::channel();
+        let writer = tokio::spawn(async move {
+            let start = Instant::now() + heartbeat.interval;
+            let mut ping = interval_at(start, heartbeat.interval);
+            ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut token = 0;
+            loop {
+                let frame = tokio::select! {
+                    frame = queue.recv() => match frame {
+                        Some(frame) => frame,
+                        None => break,
+                    },
+                    _ = ping.tick() => {
+                        token += 1;
+                        Frame::Ping { token }
+                    }
+                    _ = &mut closed => break,
+                };
+                match write_frame(&mut writer, &frame).await {
+                    Ok(()) => {}
+                    // 写失败时读任务也会发现连接断开
+                    Err(ProtocolError::Io(_)) => return,
+                    // 编码失败 (如消息太长) 当作本地错误显示
+                    Err(e) => {
+                        let message = e.to_string();
+                        let _ = errors.send(Ok(Frame::Error { message })).await;
+                    }
+                }
+            }
+            // TLS 下会发送 close_notify，服务端据此区分正常退出
+            let _ = writer.shutdown().await;
+        });
+
+        Ok(Session {
+            frames,
+            out,
+            close,
+            writer,
+        })
+    }
+
+    /// 发送一条消息，连接已断开时返回 false
+    pub async fn send(&self, frame: Frame) -> bool {
+        self.out.send(frame).await.is_ok()
+    }
+
+    /// 停止发送并关闭连接；上传任务可能还持有发送队列，不等它们
+    pub async fn close(self) {
+        let _ = self.close.send(());
+        let _ = self.writer.await;
+    }
+}
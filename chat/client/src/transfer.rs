@@ -0,0 +1,668 @@
+// 文件传输：/send 提出请求，对方 /accept 后分块经服务端转发，收完校验 SHA-256
+//
+// 每个传输有一个本地编号 (ticket)，发出的传输同时用它作协议里的 id
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sha2::{Digest, Sha256};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use protocol::{FILE_CHUNK_SIZE, Frame};
+
+pub const USAGE: &str = "/send <nick> <file>, /accept [n], /reject [n], /cancel <n>, /transfers";
+const COMMANDS: [&str; 5] = ["/send", "/accept", "/reject", "/cancel", "/transfers"];
+
+/// 命令行给出的传输设置
+#[derive(Clone)]
+pub struct Options {
+    /// 下载目录
+    pub dir: PathBuf,
+    /// 发送和接收的文件大小上限 (字节)
+    pub max_size: u64,
+}
+
+/// 传输进度和结果；peer 为空时不属于某个传输
+pub struct Notice {
+    pub peer: Option<String>,
+    pub text: String,
+}
+
+struct Outgoing {
+    peer: String,
+    name: String,
+    path: PathBuf,
+    size: u64,
+    sent: Arc<AtomicU64>,
+    // 对方接受后才开始上传
+    upload: Option<JoinHandle<()>>,
+}
+
+struct Incoming {
+    peer: String,
+    // 发送方分配的 id
+    id: u32,
+    name: String,
+    size: u64,
+    sha256: [u8; 32],
+    received: u64,
+    // 接受后才创建文件
+    download: Option<Download>,
+}
+
+struct Download {
+    file: File,
+    part: PathBuf,
+    hasher: Sha256,
+}
+
+pub struct Transfers {
+    options: Options,
+    out: mpsc::Sender<Frame>,
+    notices: mpsc::UnboundedSender<Notice>,
+    next: u32,
+    outgoing: HashMap<u32, Outgoing>,
+    incoming: HashMap<u32, Incoming>,
+}
+
+impl Transfers {
+    pub fn new(
+        options: Options,
+        out: mpsc::Sender<Frame>,
+    ) -> (Self, mpsc::UnboundedReceiver<Notice>) {
+        let (notices, rx) = mpsc::unbounded_channel();
+        let transfers = Transfers {
+            options,
+            out,
+            notices,
+            next: 0,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+        };
+        (transfers, rx)
+    }
+
+    pub fn is_command(msg: &str) -> bool {
+        let command = msg.split(' ').next().unwrap_or("");
+        COMMANDS.contains(&command)
+    }
+
+    /// 执行文件命令，返回的错误由界面显示
+    pub async fn command(&mut self, msg: &str) -> Result<(), String> {
+        let (command, arg) = match msg.split_once(' ') {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (msg, ""),
+        };
+        match command {
+            "/send" => match arg.split_once(' ') {
+                Some((peer, path)) if !path.trim().is_empty() => {
+                    self.send(peer, Path::new(path.trim())).await
+                }
+                _ => Err("usage: /send <nick> <file>".to_string()),
+            },
+            "/accept" => {
+                let ticket = self.pending(arg)?;
+                self.accept(ticket).await
+            }
+            "/reject" => {
+                let ticket = self.pending(arg)?;
+                let incoming = self.incoming.remove(&ticket).unwrap();
+                self.reply(&incoming, false).await;
+                self.notify(
+                    Some(&incoming.peer),
+                    format!("rejected {} from {}", incoming.name, incoming.peer),
+                );
+                Ok(())
+            }
+            "/cancel" => match arg.parse() {
+                Ok(ticket) => self.cancel(ticket).await,
+                Err(_) => Err("usage: /cancel <n>".to_string()),
+            },
+            "/transfers" => {
+                let list = self.list();
+                if list.is_empty() {
+                    self.notify(None, "no transfers".to_string());
+                }
+                for line in list {
+                    self.notify(None, line);
+                }
+                Ok(())
+            }
+            _ => Err("unknown command".to_string()),
+        }
+    }
+
+    /// 处理文件相关的消息，其余消息原样返回
+    pub async fn on_frame(&mut self, frame: Frame) -> Option<Frame> {
+        match frame {
+            Frame::FileOffer {
+                id,
+                peer,
+                name,
+                size,
+                sha256,
+            } => self.offered(id, peer, &name, size, sha256).await,
+            Frame::FileReply { id, peer, accept } => self.replied(id, &peer, accept),
+            Frame::FileChunk { id, peer, data } => self.received(id, &peer, &data).await,
+            Frame::FileCancel { id, peer, reason } => self.cancelled(id, &peer, &reason).await,
+            frame => return Some(frame),
+        }
+        None
+    }
+
+    /// 当前传输，每项一行，供 /transfers 和侧栏显示
+    pub fn list(&mut self) -> Vec<String> {
+        // 上传完成的不再显示
+        self.outgoing
+            .retain(|_, t| !t.upload.as_ref().is_some_and(|u| u.is_finished()));
+
+        let mut list: Vec<(u32, String)> = Vec::new();
+        for (ticket, t) in &self.outgoing {
+            let state = match t.upload {
+                Some(_) => format!("{}%", percent(t.sent.load(Ordering::Relaxed), t.size)),
+                None => "waiting".to_string(),
+            };
+            list.push((
+                *ticket,
+                format!("{} >{} {} {}", ticket, t.peer, t.name, state),
+            ));
+        }
+        for (ticket, t) in &self.incoming {
+            let state = match t.download {
+                Some(_) => format!("{}%", percent(t.received, t.size)),
+                None => "pending".to_string(),
+            };
+            list.push((
+                *ticket,
+                format!("{} <{} {} {}", ticket, t.peer, t.name, state),
+            ));
+        }
+        list.sort();
+        list.into_iter().map(|(_, line)| line).collect()
+    }
+
+    async fn send(&mut self, peer: &str, path: &Path) -> Result<(), String> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format!("{}: not a file name", path.display()))?
+            .to_string();
+        let meta = fs::metadata(path)
+            .await
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        if !meta.is_file() {
+            return Err(format!("{} is not a regular file", path.display()));
+        }
+        if meta.len() > self.options.max_size {
+            return Err(format!(
+                "{} is larger than {}",
+                name,
+                format_size(self.options.max_size)
+            ));
+        }
+
+        // 大文件哈希耗时，不占用异步工作线程
+        let owned = path.to_path_buf();
+        let sha256 = tokio::task::spawn_blocking(move || hash_file(&owned))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        self.next += 1;
+        let ticket = self.next;
+        let offer = Frame::FileOffer {
+            id: ticket,
+            peer: peer.to_string(),
+            name: name.clone(),
+            size: meta.len(),
+            sha256,
+        };
+        if self.out.send(offer).await.is_err() {
+            return Err("connection closed".to_string());
+        }
+        self.notify(
+            Some(peer),
+            format!(
+                "offered {} ({}) to {}, ticket {}",
+                name,
+                format_size(meta.len()),
+                peer,
+                ticket
+            ),
+        );
+        self.outgoing.insert(
+            ticket,
+            Outgoing {
+                peer: peer.to_string(),
+                name,
+                path: path.to_path_buf(),
+                size: meta.len(),
+                sent: Arc::new(AtomicU64::new(0)),
+                upload: None,
+            },
+        );
+        Ok(())
+    }
+
+    // 不指定编号时选最近一个等待回复的请求
+    fn pending(&self, arg: &str) -> Result<u32, String> {
+        let ticket = if arg.is_empty() {
+            self.incoming
+                .iter()
+                .filter(|(_, t)| t.download.is_none())
+                .map(|(ticket, _)| *ticket)
+                .max()
+                .ok_or_else(|| "no pending file offers".to_string())?
+        } else {
+            arg.parse()
+                .map_err(|_| format!("not a transfer number: {}", arg))?
+        };
+        match self.incoming.get(&ticket) {
+            Some(t) if t.download.is_none() => Ok(ticket),
+            _ => Err(format!("no pending file offer {}", ticket)),
+        }
+    }
+
+    async fn accept(&mut self, ticket: u32) -> Result<(), String> {
+        fs::create_dir_all(&self.options.dir)
+            .await
+            .map_err(|e| format!("{}: {}", self.options.dir.display(), e))?;
+        let incoming = self.incoming.get(&ticket).unwrap();
+        let part = unique_path(&self.options.dir, &format!("{}.part", incoming.name));
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part)
+            .await
+            .map_err(|e| format!("{}: {}", part.display(), e))?;
+
+        let incoming = self.incoming.get_mut(&ticket).unwrap();
+        incoming.download = Some(Download {
+            file,
+            part,
+            hasher: Sha256::new(),
+        });
+        let incoming = self.incoming.get(&ticket).unwrap();
+        self.reply(incoming, true).await;
+        self.notify(
+            Some(&incoming.peer),
+            format!("receiving {} from {}", incoming.name, incoming.peer),
+        );
+        // 空文件没有数据块，接受即完成
+        if incoming.size == 0 {
+            self.finish(ticket).await;
+        }
+        Ok(())
+    }
+
+    async fn cancel(&mut self, ticket: u32) -> Result<(), String> {
+        let (id, peer, name) = if let Some(t) = self.outgoing.remove(&ticket) {
+            if let Some(upload) = t.upload {
+                upload.abort();
+            }
+            (ticket, t.peer, t.name)
+        } else if let Some(t) = self.incoming.remove(&ticket) {
+            if let Some(download) = t.download {
+                let _ = fs::remove_file(&download.part).await;
+            }
+            (t.id, t.peer, t.name)
+        } else {
+            return Err(format!("no transfer {}", ticket));
+        };
+
+        let cancel = Frame::FileCancel {
+            id,
+            peer: peer.clone(),
+            reason: "cancelled".to_string(),
+        };
+        let _ = self.out.send(cancel).await;
+        self.notify(Some(&peer), format!("cancelled {}", name));
+        Ok(())
+    }
+
+    async fn offered(&mut self, id: u32, peer: String, name: &str, size: u64, sha256: [u8; 32]) {
+        let incoming = Incoming {
+            peer,
+            id,
+            name: sanitize(name),
+            size,
+            sha256,
+            received: 0,
+            download: None,
+        };
+        if size > self.options.max_size {
+            self.reply(&incoming, false).await;
+            self.notify(
+                Some(&incoming.peer),
+                format!(
+                    "rejected {} ({}) from {}: larger than {}",
+                    incoming.name,
+                    format_size(size),
+                    incoming.peer,
+                    format_size(self.options.max_size)
+                ),
+            );
+            return;
+        }
+
+        self.next += 1;
+        let ticket = self.next;
+        self.notify(
+            Some(&incoming.peer),
+            format!(
+                "{} offers {} ({}), /accept {} or /reject {}",
+                incoming.peer,
+                incoming.name,
+                format_size(size),
+                ticket,
+                ticket
+            ),
+        );
+        self.incoming.insert(ticket, incoming);
+    }
+
+    fn replied(&mut self, id: u32, peer: &str, accept: bool) {
+        let Some(t) = self.outgoing.get_mut(&id).filter(|t| t.peer == peer) else {
+            return;
+        };
+        if !accept {
+            let t = self.outgoing.remove(&id).unwrap();
+            self.notify(Some(peer), format!("{} rejected {}", peer, t.name));
+            return;
+        }
+
+        let upload = Upload {
+            out: self.out.clone(),
+            notices: self.notices.clone(),
+            id,
+            peer: t.peer.clone(),
+            name: t.name.clone(),
+            path: t.path.clone(),
+            size: t.size,
+            sent: t.sent.clone(),
+        };
+        t.upload = Some(tokio::spawn(upload.run()));
+        let text = format!("{} accepted {}", peer, t.name);
+        self.notify(Some(peer), text);
+    }
+
+    async fn received(&mut self, id: u32, peer: &str, data: &[u8]) {
+        let Some(ticket) = self.find_incoming(id, peer) else {
+            return;
+        };
+        let t = self.incoming.get_mut(&ticket).unwrap();
+        let Some(download) = t.download.as_mut() else {
+            return;
+        };
+
+        let before = percent(t.received, t.size) / 10;
+        t.received += data.len() as u64;
+        if t.received > t.size {
+            return self
+                .abort(ticket, "file larger than announced".to_string())
+                .await;
+        }
+        if let Err(e) = download.file.write_all(data).await {
+            return self.abort(ticket, format!("write failed: {}", e)).await;
+        }
+        download.hasher.update(data);
+
+        if t.received == t.size {
+            return self.finish(ticket).await;
+        }
+        let done = percent(t.received, t.size);
+        if done / 10 > before {
+            let text = format!("receiving {}: {}%", t.name, done);
+            let peer = t.peer.clone();
+            self.notify(Some(&peer), text);
+        }
+    }
+
+    async fn cancelled(&mut self, id: u32, peer: &str, reason: &str) {
+        // 与服务端一致：先当作对方发来的传输，再当作我们发出的
+        let name = if let Some(ticket) = self.find_incoming(id, peer) {
+            let t = self.incoming.remove(&ticket).unwrap();
+            if let Some(download) = t.download {
+                let _ = fs::remove_file(&download.part).await;
+            }
+            t.name
+        } else if self.outgoing.get(&id).is_some_and(|t| t.peer == peer) {
+            let t = self.outgoing.remove(&id).unwrap();
+            if let Some(upload) = t.upload {
+                upload.abort();
+            }
+            t.name
+        } else {
+            return;
+        };
+        self.notify(
+            Some(peer),
+            format!("transfer of {} cancelled: {}", name, reason),
+        );
+    }
+
+    // 收完：校验哈希后改成正式文件名，校验失败删除
+    async fn finish(&mut self, ticket: u32) {
+        let t = self.incoming.remove(&ticket).unwrap();
+        let Some(mut download) = t.download else {
+            return;
+        };
+        let flushed = download.file.flush().await;
+        drop(download.file);
+
+        let digest: [u8; 32] = download.hasher.finalize().into();
+        let text = if let Err(e) = flushed {
+            let _ = fs::remove_file(&download.part).await;
+            format!("saving {} failed: {}", t.name, e)
+        } else if digest != t.sha256 {
+            let _ = fs::remove_file(&download.part).await;
+            format!(
+                "{} from {} failed the SHA-256 check, discarded",
+                t.name, t.peer
+            )
+        } else {
+            let path = unique_path(&self.options.dir, &t.name);
+            match fs::rename(&download.part, &path).await {
+                Ok(()) => format!("saved {} from {} to {}", t.name, t.peer, path.display()),
+                Err(e) => format!("saving {} failed: {}", path.display(), e),
+            }
+        };
+        self.notify(Some(&t.peer), text);
+    }
+
+    // 本地出错，删除文件并通知对方
+    async fn abort(&mut self, ticket: u32, reason: String) {
+        let t = self.incoming.remove(&ticket).unwrap();
+        if let Some(download) = t.download {
+            let _ = fs::remove_file(&download.part).await;
+        }
+        self.notify(
+            Some(&t.peer),
+            format!("receiving {} failed: {}", t.name, reason),
+        );
+        let cancel = Frame::FileCancel {
+            id: t.id,
+            peer: t.peer,
+            reason,
+        };
+        let _ = self.out.send(cancel).await;
+    }
+
+    async fn reply(&self, incoming: &Incoming, accept: bool) {
+        let reply = Frame::FileReply {
+            id: incoming.id,
+            peer: incoming.peer.clone(),
+            accept,
+        };
+        let _ = self.out.send(reply).await;
+    }
+
+    fn find_incoming(&self, id: u32, peer: &str) -> Option<u32> {
+        self.incoming
+            .iter()
+            .find(|(_, t)| t.id == id && t.peer == peer)
+            .map(|(ticket, _)| *ticket)
+    }
+
+    fn notify(&self, peer: Option<&str>, text: String) {
+        let notice = Notice {
+            peer: peer.map(str::to_string),
+            text,
+        };
+        let _ = self.notices.send(notice);
+    }
+}
+
+impl Drop for Transfers {
+    // 退出时删除没收完的文件
+    fn drop(&mut self) {
+        for t in self.incoming.values() {
+            if let Some(download) = &t.download {
+                let _ = std::fs::remove_file(&download.part);
+            }
+        }
+    }
+}
+
+// 上传任务，经发送队列发出数据块，队列满时自然等待
+struct Upload {
+    out: mpsc::Sender<Frame>,
+    notices: mpsc::UnboundedSender<Notice>,
+    id: u32,
+    peer: String,
+    name: String,
+    path: PathBuf,
+    size: u64,
+    sent: Arc<AtomicU64>,
+}
+
+impl Upload {
+    async fn run(self) {
+        let text = match self.send_chunks().await {
+            Ok(()) => format!("sent {} to {}", self.name, self.peer),
+            Err(e) => {
+                let cancel = Frame::FileCancel {
+                    id: self.id,
+                    peer: self.peer.clone(),
+                    reason: e.to_string(),
+                };
+                let _ = self.out.send(cancel).await;
+                format!("sending {} failed: {}", self.name, e)
+            }
+        };
+        self.notify(text);
+    }
+
+    async fn send_chunks(&self) -> io::Result<()> {
+        let mut file = File::open(&self.path).await?;
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+        let mut sent = 0;
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            // 提出请求后文件被改动，对方的哈希校验也会失败，这里提前停下
+            if sent + n as u64 > self.size {
+                return Err(io::Error::other("file changed while sending"));
+            }
+            let chunk = Frame::FileChunk {
+                id: self.id,
+                peer: self.peer.clone(),
+                data: buf[..n].to_vec(),
+            };
+            if self.out.send(chunk).await.is_err() {
+                return Err(io::Error::other("connection closed"));
+            }
+
+            let before = percent(sent, self.size) / 10;
+            sent += n as u64;
+            self.sent.store(sent, Ordering::Relaxed);
+            let done = percent(sent, self.size);
+            if done / 10 > before && sent < self.size {
+                self.notify(format!("sending {}: {}%", self.name, done));
+            }
+        }
+        if sent < self.size {
+            return Err(io::Error::other("file changed while sending"));
+        }
+        Ok(())
+    }
+
+    fn notify(&self, text: String) {
+        let notice = Notice {
+            peer: Some(self.peer.clone()),
+            text,
+        };
+        let _ = self.notices.send(notice);
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; FILE_CHUNK_SIZE];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hasher.finalize().into()),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+// 对方给的文件名只保留最后一段，去掉路径分隔符和开头的点
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim_start_matches('.').trim();
+    if name.is_empty() {
+        "download".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+// 不覆盖已有文件：a.txt 存在时依次尝试 a (1).txt、a (2).txt ...
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, ext)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn percent(done: u64, size: u64) -> u64 {
+    (done * 100).checked_div(size).unwrap_or(100)
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
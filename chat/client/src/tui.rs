@@ -0,0 +1,783 @@
+// 全屏界面：消息区、带历史的输入行、窗口/房间侧栏
+//
+// 每个房间和私聊对象各有一个窗口，非当前窗口收到消息时在侧栏显示未读数
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, Paragraph, Wrap};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+
+use protocol::{Frame, RoomInfo, Status};
+
+use crate::command::{MOD_USAGE, USAGE, parse_command};
+use crate::session::Session;
+use crate::transfer::{self, Notice, Transfers};
+use crate::{Settings, format_time};
+
+// 服务端通知和错误默认显示在状态窗口
+const STATUS: &str = "*status*";
+const SIDEBAR_WIDTH: u16 = 26;
+// 每个窗口保留的消息行数
+const MAX_LINES: usize = 2000;
+// 输入历史条数
+const MAX_INPUT_HISTORY: usize = 200;
+// 正在输入提示：输入期间每隔 TYPING_REFRESH 重发一次，
+// 超过 TYPING_EXPIRE 没收到对方的提示就不再显示
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+const TYPING_EXPIRE: Duration = Duration::from_secs(6);
+// 定时检查过期的输入提示、自动离开和待刷新的侧栏
+const TICK: Duration = Duration::from_secs(1);
+// 侧栏房间列表的最短刷新间隔，成员频繁进出时不会触发服务端限流
+const ROOMS_REFRESH: Duration = Duration::from_secs(1);
+const KEYS: &str =
+    "Tab/Alt+1..9 switch window, PgUp/PgDn scroll, Up/Down input history, Ctrl+C quit";
+const NICK_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Magenta,
+    Color::Blue,
+    Color::LightRed,
+];
+
+struct Window {
+    name: String,
+    lines: Vec<Line<'static>>,
+    unread: usize,
+}
+
+struct App {
+    nick: String,
+    // 服务端认为我们所在的房间
+    room: Option<String>,
+    rooms: Vec<RoomInfo>,
+    windows: Vec<Window>,
+    active: usize,
+    // 距离底部的滚动行数，0 表示跟随最新消息
+    scroll: u16,
+    // 消息区高度，翻页用
+    page: u16,
+    input: String,
+    // 光标位置 (字符数)
+    cursor: usize,
+    history: Vec<String>,
+    history_pos: Option<usize>,
+    // 用户手动 /list 时把结果打印出来，自动刷新侧栏的不打印
+    show_rooms: bool,
+    // 待执行的文件命令，由主循环交给 Transfers
+    file_commands: Vec<String>,
+    // 侧栏显示的传输进度
+    transfers: Vec<String>,
+    // 自己的在线状态，以服务端的通知为准
+    away: bool,
+    // 因无操作自动标记的离开，按键后自动恢复
+    auto_away: bool,
+    away_after: Option<Duration>,
+    last_key: Instant,
+    // 是否发送正在输入提示，以及上次发送的时间
+    send_typing: bool,
+    typing_sent: Option<Instant>,
+    // 正在输入的人：(房间, 昵称, 收到提示的时间)
+    typing: Vec<(String, String, Instant)>,
+    // 上次请求房间列表的时间，以及是否有推迟的刷新
+    rooms_requested: Option<Instant>,
+    rooms_stale: bool,
+    quit: bool,
+}
+
+pub async fn run<S>(stream: S, hello: Frame, settings: Settings) -> Result<(), String>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let nick = match &hello {
+        Frame::Nick { nick } | Frame::Login { nick, .. } => nick.clone(),
+        _ => String::new(),
+    };
+    let mut session = Session::start(stream, hello, settings.heartbeat).await?;
+    let (mut transfers, mut notices) = Transfers::new(settings.transfer, session.out.clone());
+
+    // crossterm 读终端事件是阻塞的，放在单独线程
+    let (event_tx, mut events) = mpsc::channel(64);
+    thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if event_tx.blocking_send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut terminal = ratatui::init();
+    let mut app = App::new(nick, settings.typing, settings.away_after);
+    let mut tick = tokio::time::interval(TICK);
+    let result = loop {
+        app.transfers = transfers.list();
+        if let Err(e) = terminal.draw(|f| app.draw(f)) {
+            break Err(e.to_string());
+        }
+
+        let requests = tokio::select! {
+            frame = session.frames.recv() => match frame {
+                Some(Ok(frame)) => match transfers.on_frame(frame).await {
+                    Some(frame) => app.on_frame(frame),
+                    None => Vec::new(),
+                },
+                Some(Err(e)) if e.is_recoverable() => {
+                    app.error(format!("bad frame from server: {}", e));
+                    Vec::new()
+                }
+                _ => break Err("connection with server was severed".to_string()),
+            },
+            event = events.recv() => match event {
+                Some(Event::Key(key)) => app.on_key(key),
+                Some(_) => Vec::new(),
+                None => break Ok(()),
+            },
+            Some(notice) = notices.recv() => {
+                app.on_notice(notice);
+                Vec::new()
+            }
+            _ = tick.tick() => app.on_tick(),
+        };
+
+        for command in std::mem::take(&mut app.file_commands) {
+            if let Err(e) = transfers.command(&command).await {
+                app.error(e);
+            }
+        }
+        let mut sent = true;
+        for frame in requests {
+            sent &= session.send(frame).await;
+        }
+        if !sent {
+            break Err("connection with server was severed".to_string());
+        }
+        if app.quit {
+            break Ok(());
+        }
+    };
+
+    ratatui::restore();
+    session.close().await;
+    result
+}
+
+impl App {
+    fn new(nick: String, send_typing: bool, away_after: Option<Duration>) -> Self {
+        let mut app = App {
+            nick,
+            room: None,
+            rooms: Vec::new(),
+            windows: Vec::new(),
+            active: 0,
+            scroll: 0,
+            page: 1,
+            input: String::new(),
+            cursor: 0,
+            history: Vec::new(),
+            history_pos: None,
+            show_rooms: false,
+            file_commands: Vec::new(),
+            transfers: Vec::new(),
+            away: false,
+            auto_away: false,
+            away_after,
+            last_key: Instant::now(),
+            send_typing,
+            typing_sent: None,
+            typing: Vec::new(),
+            rooms_requested: None,
+            rooms_stale: false,
+            quit: false,
+        };
+        app.notice(
+            STATUS,
+            format!(
+                "commands: {}, {}, {}, /query <nick>, /close, /quit",
+                USAGE,
+                transfer::USAGE,
+                MOD_USAGE
+            ),
+        );
+        app.notice(STATUS, KEYS.to_string());
+        app
+    }
+
+    /// 处理服务端消息，返回需要发给服务端的请求
+    fn on_frame(&mut self, frame: Frame) -> Vec<Frame> {
+        let mut requests = Vec::new();
+        match frame {
+            Frame::Chat {
+                room,
+                from,
+                timestamp,
+                text,
+            } => {
+                self.stop_typing(&room, &from);
+                let line = chat_line(timestamp, &from, &text);
+                self.push(&room, line);
+            }
+            Frame::Private {
+                to,
+                from,
+                timestamp,
+                text,
+            } => {
+                let peer = if from == self.nick { to } else { from.clone() };
+                let line = chat_line(timestamp, &from, &text);
+                self.push(&format!("@{}", peer), line);
+            }
+            Frame::Join { room, who } => {
+                if who == self.nick {
+                    self.room = Some(room.clone());
+                    let index = self.window(&room);
+                    self.select(index);
+                }
+                self.notice(&room, format!("{} joined {}", who, room));
+                requests.extend(self.refresh_rooms());
+            }
+            Frame::Leave { room, who } => {
+                if who == self.nick && self.room.as_ref() == Some(&room) {
+                    self.room = None;
+                }
+                self.stop_typing(&room, &who);
+                self.notice(&room, format!("{} left {}", who, room));
+                requests.extend(self.refresh_rooms());
+            }
+            Frame::Renamed { old, new } => {
+                self.typing.retain(|(_, who, _)| *who != old);
+                let target = self.room.clone().unwrap_or_else(|| STATUS.to_string());
+                self.notice(&target, format!("{} is now known as {}", old, new));
+                requests.extend(self.refresh_rooms());
+            }
+            Frame::Welcome { nick } => {
+                self.notice(STATUS, format!("you are now known as {}", nick));
+                self.nick = nick;
+            }
+            Frame::Error { message } => self.error(message),
+            // 管理操作可能改变了成员或管理员，刷新侧栏
+            Frame::Notice { room, text } => {
+                let name = match room.as_str() {
+                    "" => self.windows[self.active].name.clone(),
+                    _ => room,
+                };
+                self.notice(&name, text);
+                requests.extend(self.refresh_rooms());
+            }
+            // 别人的状态只在侧栏显示
+            Frame::Presence { who, status } => {
+                if who == self.nick {
+                    self.away = status == Status::Away;
+                    self.auto_away &= self.away;
+                    let target = self.room.clone().unwrap_or_else(|| STATUS.to_string());
+                    let text = match status {
+                        Status::Away => "you are away",
+                        Status::Online => "you are back",
+                    };
+                    self.notice(&target, text.to_string());
+                }
+                requests.extend(self.refresh_rooms());
+            }
+            Frame::Typing { room, who, typing } => {
+                if who != self.nick {
+                    self.stop_typing(&room, &who);
+                    if typing {
+                        self.typing.push((room, who, Instant::now()));
+                    }
+                }
+            }
+            Frame::Rooms { rooms } => {
+                if std::mem::take(&mut self.show_rooms) {
+                    let name = self.windows[self.active].name.clone();
+                    for room in &rooms {
+                        let text = format!(
+                            "{} ({}): {}",
+                            room.name,
+                            room.members.len(),
+                            room.members.join(", ")
+                        );
+                        self.notice(&name, text);
+                    }
+                }
+                self.rooms = rooms;
+            }
+            Frame::Nick { .. }
+            | Frame::List
+            | Frame::History { .. }
+            | Frame::Login { .. }
+            | Frame::Register { .. }
+            | Frame::FileOffer { .. }
+            | Frame::FileReply { .. }
+            | Frame::FileChunk { .. }
+            | Frame::FileCancel { .. }
+            | Frame::Moderate { .. }
+            | Frame::Ping { .. }
+            | Frame::Pong { .. }
+            | Frame::Link { .. }
+            | Frame::Server { .. }
+            | Frame::Split { .. }
+            | Frame::User { .. }
+            | Frame::Quit { .. } => {}
+        }
+        requests
+    }
+
+    // 清理过期的输入提示，长时间无操作时自动标记为离开
+    fn on_tick(&mut self) -> Vec<Frame> {
+        let now = Instant::now();
+        self.typing
+            .retain(|(_, _, at)| now.duration_since(*at) < TYPING_EXPIRE);
+        let mut requests = Vec::new();
+        if let Some(after) = self.away_after
+            && !self.away
+            && now.duration_since(self.last_key) >= after
+        {
+            // 先记下，避免服务端确认前重复发送
+            self.away = true;
+            self.auto_away = true;
+            requests.push(presence(Status::Away));
+        }
+        if self.rooms_stale {
+            requests.extend(self.refresh_rooms());
+        }
+        requests
+    }
+
+    // 刷新侧栏的房间列表，距上次请求太近时推迟到下一次定时检查
+    fn refresh_rooms(&mut self) -> Option<Frame> {
+        let now = Instant::now();
+        match self.rooms_requested {
+            Some(at) if now.duration_since(at) < ROOMS_REFRESH => {
+                self.rooms_stale = true;
+                None
+            }
+            _ => {
+                self.rooms_requested = Some(now);
+                self.rooms_stale = false;
+                Some(Frame::List)
+            }
+        }
+    }
+
+    fn stop_typing(&mut self, room: &str, nick: &str) {
+        self.typing
+            .retain(|(r, who, _)| !(r == room && who == nick));
+    }
+
+    // 窗口底部的提示，如 "alice, bob are typing..."
+    fn typing_line(&self, room: &str) -> Option<String> {
+        let names: Vec<&str> = self
+            .typing
+            .iter()
+            .filter(|(r, _, _)| r == room)
+            .map(|(_, who, _)| who.as_str())
+            .collect();
+        match names.len() {
+            0 => None,
+            1 => Some(format!(" {} is typing... ", names[0])),
+            2 | 3 => Some(format!(" {} are typing... ", names.join(", "))),
+            _ => Some(" several people are typing... ".to_string()),
+        }
+    }
+
+    // 在当前房间窗口输入普通消息时发送正在输入提示，输入清空后发送停止
+    fn update_typing(&mut self) -> Option<Frame> {
+        let in_room = self.room.as_deref() == Some(self.windows[self.active].name.as_str());
+        let typing = self.send_typing
+            && in_room
+            && !self.input.trim().is_empty()
+            && !self.input.starts_with('/');
+        let now = Instant::now();
+        match (typing, self.typing_sent) {
+            (true, Some(sent)) if now.duration_since(sent) < TYPING_REFRESH => None,
+            (true, _) => {
+                self.typing_sent = Some(now);
+                Some(typing_frame(true))
+            }
+            (false, Some(_)) => {
+                self.typing_sent = None;
+                Some(typing_frame(false))
+            }
+            (false, None) => None,
+        }
+    }
+
+    // 传输通知显示在与对方的私聊窗口
+    fn on_notice(&mut self, notice: Notice) {
+        let name = match notice.peer {
+            Some(peer) => format!("@{}", peer),
+            None => self.windows[self.active].name.clone(),
+        };
+        self.notice(&name, notice.text);
+    }
+
+    fn on_key(&mut self, key: KeyEvent) -> Vec<Frame> {
+        if key.kind != KeyEventKind::Press {
+            return Vec::new();
+        }
+        self.last_key = Instant::now();
+        let mut requests = Vec::new();
+        if std::mem::take(&mut self.auto_away) {
+            self.away = false;
+            requests.push(presence(Status::Online));
+        }
+        requests.extend(self.edit(key));
+        requests.extend(self.update_typing());
+        requests
+    }
+
+    fn edit(&mut self, key: KeyEvent) -> Vec<Frame> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+
+        match key.code {
+            KeyCode::Char('c') | KeyCode::Char('d') if ctrl => self.quit = true,
+            KeyCode::Char('n') if ctrl => self.select((self.active + 1) % self.windows.len()),
+            KeyCode::Char('p') if ctrl => self.select_previous(),
+            KeyCode::Char(c @ '1'..='9') if alt => self.select(c as usize - '1' as usize),
+            KeyCode::Tab => self.select((self.active + 1) % self.windows.len()),
+            KeyCode::BackTab => self.select_previous(),
+            KeyCode::Enter => return self.submit(),
+            KeyCode::Char(c) => {
+                let at = self.byte_index(self.cursor);
+                self.input.insert(at, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                let at = self.byte_index(self.cursor);
+                self.input.remove(at);
+            }
+            KeyCode::Delete if self.cursor < self.input.chars().count() => {
+                let at = self.byte_index(self.cursor);
+                self.input.remove(at);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.chars().count()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.chars().count(),
+            KeyCode::Esc => {
+                self.input.clear();
+                self.cursor = 0;
+                self.history_pos = None;
+            }
+            KeyCode::Up => self.recall(true),
+            KeyCode::Down => self.recall(false),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_add(self.page),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.page),
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    fn submit(&mut self) -> Vec<Frame> {
+        let line = std::mem::take(&mut self.input);
+        self.cursor = 0;
+        self.history_pos = None;
+        self.scroll = 0;
+
+        let msg = line.trim();
+        if msg.is_empty() {
+            return Vec::new();
+        }
+        // 带密码的命令不进输入历史
+        let secret = msg.starts_with("/login ") || msg.starts_with("/register ");
+        if !secret && self.history.last().map(String::as_str) != Some(msg) {
+            self.history.push(msg.to_string());
+            if self.history.len() > MAX_INPUT_HISTORY {
+                self.history.remove(0);
+            }
+        }
+
+        let name = self.windows[self.active].name.clone();
+        let (command, arg) = msg.split_once(' ').unwrap_or((msg, ""));
+        match command {
+            "/quit" | ":quit" => {
+                self.quit = true;
+                return Vec::new();
+            }
+            "/query" if !arg.trim().is_empty() => {
+                let index = self.window(&format!("@{}", arg.trim()));
+                self.select(index);
+                return Vec::new();
+            }
+            "/close" => {
+                if name == STATUS || self.room.as_ref() == Some(&name) {
+                    self.error("cannot close this window".to_string());
+                } else {
+                    self.windows.remove(self.active);
+                    self.select(self.active.min(self.windows.len() - 1));
+                }
+                return Vec::new();
+            }
+            _ if Transfers::is_command(msg) => {
+                self.file_commands.push(msg.to_string());
+                return Vec::new();
+            }
+            _ => {}
+        }
+
+        // 普通消息发往当前窗口对应的私聊对象或房间
+        if !msg.starts_with('/') {
+            if let Some(peer) = name.strip_prefix('@') {
+                return vec![Frame::Private {
+                    to: peer.to_string(),
+                    from: String::new(),
+                    timestamp: 0,
+                    text: msg.to_string(),
+                }];
+            }
+            if name.starts_with('#') && self.room.as_ref() != Some(&name) {
+                self.error(format!("not in {}, /join {} first", name, name));
+                return Vec::new();
+            }
+        }
+
+        match parse_command(msg) {
+            Ok(Frame::List) => {
+                self.show_rooms = true;
+                vec![Frame::List]
+            }
+            Ok(frame) => vec![frame],
+            Err(usage) => {
+                self.error(usage.to_string());
+                Vec::new()
+            }
+        }
+    }
+
+    fn recall(&mut self, older: bool) {
+        if self.history.is_empty() {
+            return;
+        }
+        self.history_pos = match (self.history_pos, older) {
+            (None, true) => Some(self.history.len() - 1),
+            (None, false) => None,
+            (Some(pos), true) => Some(pos.saturating_sub(1)),
+            (Some(pos), false) if pos + 1 < self.history.len() => Some(pos + 1),
+            (Some(_), false) => None,
+        };
+        self.input = match self.history_pos {
+            Some(pos) => self.history[pos].clone(),
+            None => String::new(),
+        };
+        self.cursor = self.input.chars().count();
+    }
+
+    fn byte_index(&self, chars: usize) -> usize {
+        self.input
+            .char_indices()
+            .nth(chars)
+            .map_or(self.input.len(), |(i, _)| i)
+    }
+
+    // 按名字找窗口，没有就新建
+    fn window(&mut self, name: &str) -> usize {
+        if let Some(index) = self.windows.iter().position(|w| w.name == name) {
+            return index;
+        }
+        self.windows.push(Window {
+            name: name.to_string(),
+            lines: Vec::new(),
+            unread: 0,
+        });
+        self.windows.len() - 1
+    }
+
+    fn select(&mut self, index: usize) {
+        if let Some(window) = self.windows.get_mut(index) {
+            window.unread = 0;
+            self.active = index;
+            self.scroll = 0;
+        }
+    }
+
+    fn select_previous(&mut self) {
+        let count = self.windows.len();
+        self.select((self.active + count - 1) % count);
+    }
+
+    fn push(&mut self, name: &str, line: Line<'static>) {
+        let index = self.window(name);
+        let window = &mut self.windows[index];
+        window.lines.push(line);
+        if window.lines.len() > MAX_LINES {
+            window.lines.remove(0);
+        }
+        if index != self.active {
+            window.unread += 1;
+        } else if self.scroll > 0 {
+            // 正在往回翻时保持位置不动
+            self.scroll = self.scroll.saturating_add(1);
+        }
+    }
+
+    fn notice(&mut self, name: &str, text: String) {
+        let line = Line::styled(
+            format!("* {}", text),
+            Style::new().fg(Color::DarkGray).italic(),
+        );
+        self.push(name, line);
+    }
+
+    // 错误显示在当前窗口
+    fn error(&mut self, message: String) {
+        let name = self.windows[self.active].name.clone();
+        self.push(&name, Line::styled(format!("! {}", message), Color::Red));
+    }
+
+    fn draw(&mut self, f: &mut ratatui::Frame) {
+        let [main, side] =
+            Layout::horizontal([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)])
+                .areas(f.area());
+        let [messages, input] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(main);
+
+        // 消息区：自动换行，scroll 为距离底部的行数
+        let window = &self.windows[self.active];
+        let mut title = format!(" {} ", window.name);
+        if self.scroll > 0 {
+            title.push_str("[scrolled] ");
+        }
+        let mut block = Block::bordered().title(title);
+        if let Some(typing) = self.typing_line(&window.name) {
+            block = block.title_bottom(Line::from(typing).dark_gray().italic());
+        }
+        let inner = block.inner(messages);
+        let text = Paragraph::new(window.lines.clone()).wrap(Wrap { trim: false });
+        let total = text.line_count(inner.width).min(u16::MAX as usize) as u16;
+        let max_scroll = total.saturating_sub(inner.height);
+        self.page = inner.height.max(1);
+        self.scroll = self.scroll.min(max_scroll);
+        f.render_widget(
+            text.block(block).scroll((max_scroll - self.scroll, 0)),
+            messages,
+        );
+
+        // 输入行：光标超出宽度时水平滚动
+        let title = match self.away {
+            true => format!(" {} (away) ", self.nick),
+            false => format!(" {} ", self.nick),
+        };
+        let block = Block::bordered().title(title);
+        let inner = block.inner(input);
+        let cursor_x = Span::raw(&self.input[..self.byte_index(self.cursor)]).width() as u16;
+        let offset = cursor_x.saturating_sub(inner.width.saturating_sub(1));
+        f.render_widget(
+            Paragraph::new(self.input.as_str())
+                .block(block)
+                .scroll((0, offset)),
+            input,
+        );
+        f.set_cursor_position(Position::new(inner.x + cursor_x - offset, inner.y));
+
+        // 有传输时在底部显示进度
+        let transfers_height = match self.transfers.len() {
+            0 => 0,
+            n => n as u16 + 2,
+        };
+        let [windows, rooms, transfers] = Layout::vertical([
+            Constraint::Length(self.windows.len() as u16 + 2),
+            Constraint::Min(3),
+            Constraint::Length(transfers_height),
+        ])
+        .areas(side);
+
+        let items: Vec<ListItem> = self
+            .windows
+            .iter()
+            .enumerate()
+            .map(|(i, window)| {
+                let mut text = format!("{} {}", i + 1, window.name);
+                let mut style = Style::new();
+                if window.unread > 0 {
+                    text.push_str(&format!(" ({})", window.unread));
+                    style = style.fg(Color::Yellow).bold();
+                }
+                if i == self.active {
+                    style = style.reversed();
+                }
+                ListItem::new(text).style(style)
+            })
+            .collect();
+        f.render_widget(
+            List::new(items).block(Block::bordered().title(" windows ")),
+            windows,
+        );
+
+        // 所有房间，当前房间展开成员列表
+        let mut items = Vec::new();
+        for room in &self.rooms {
+            let current = self.room.as_ref() == Some(&room.name);
+            let text = format!("{} ({})", room.name, room.members.len());
+            items.push(match current {
+                true => ListItem::new(text).green().bold(),
+                false => ListItem::new(text),
+            });
+            if current {
+                // 管理员名字前加 @，暂时离开的显示为灰色
+                for member in &room.members {
+                    let mark = if room.ops.contains(member) { "@" } else { " " };
+                    let item = match room.away.contains(member) {
+                        true => ListItem::new(format!(" {}{} (away)", mark, member)).dark_gray(),
+                        false => ListItem::new(format!(" {}{}", mark, member))
+                            .style(Style::new().fg(nick_color(member))),
+                    };
+                    items.push(item);
+                }
+            }
+        }
+        f.render_widget(
+            List::new(items).block(Block::bordered().title(" rooms ")),
+            rooms,
+        );
+
+        if !self.transfers.is_empty() {
+            let items: Vec<ListItem> = self
+                .transfers
+                .iter()
+                .map(|line| ListItem::new(line.as_str()))
+                .collect();
+            f.render_widget(
+                List::new(items).block(Block::bordered().title(" transfers ")),
+                transfers,
+            );
+        }
+    }
+}
+
+fn presence(status: Status) -> Frame {
+    Frame::Presence {
+        who: String::new(),
+        status,
+    }
+}
+
+fn typing_frame(typing: bool) -> Frame {
+    Frame::Typing {
+        room: String::new(),
+        who: String::new(),
+        typing,
+    }
+}
+
+fn chat_line(timestamp: u64, from: &str, text: &str) -> Line<'static> {
+    Line::from(vec![
+        Span::styled(format!("[{}] ", format_time(timestamp)), Color::DarkGray),
+        Span::styled(from.to_string(), Style::new().fg(nick_color(from)).bold()),
+        Span::raw(": "),
+        Span::raw(text.to_string()),
+    ])
+}
+
+// 同一个昵称总是同一种颜色
+fn nick_color(nick: &str) -> Color {
+    let hash = nick
+        .bytes()
+        .fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize));
+    NICK_COLORS[hash % NICK_COLORS.len()]
+}
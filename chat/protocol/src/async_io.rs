@@ -0,0 +1,27 @@
+// tokio 版本的帧读写，与 read_frame / write_frame 行为一致
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{Frame, HEADER_SIZE, ProtocolError, parse_header};
+
+/// 读取一帧。取消后连接上可能残留半帧，不要在 select! 中与其他分支竞争
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Frame, ProtocolError> {
+    let mut header = [0u8; HEADER_SIZE];
+    if reader.read(&mut header[..1]).await? == 0 {
+        return Err(ProtocolError::Closed);
+    }
+    reader.read_exact(&mut header[1..]).await?;
+    let (msg_type, len) = parse_header(&header)?;
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Frame::decode(msg_type, &payload)
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &Frame,
+) -> Result<(), ProtocolError> {
+    writer.write_all(&frame.encode()?).await?;
+    writer.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,780 @@
+// 聊天协议：长度前缀的二进制帧
+//
+// +-----+------+--------+----------+
+// | VER | TYPE | LENGTH | PAYLOAD  |
+// +-----+------+--------+----------+
+// |  1  |  1   |   4    | LENGTH   |
+// +-----+------+--------+----------+
+//
+// LENGTH 为大端 u32，PAYLOAD 内的字符串字段为 [u16 长度][UTF-8 字节]，整数字段为大端
+//
+// 开启 serde 特性后 Frame 还有 JSON 表示，供浏览器客户端使用：
+// {"type": "chat", "text": "hi"}，类型名为变体名的 snake_case，
+// 服务端填写的字段可以省略，sha256 为十六进制，文件数据为 base64
+use std::fmt;
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "tokio")]
+pub mod async_io;
+#[cfg(feature = "serde")]
+mod json;
+
+// 0x02: RoomInfo 增加管理员和离开状态
+pub const PROTOCOL_VERSION: u8 = 0x02;
+
+// 帧头长度 VER + TYPE + LENGTH
+pub const HEADER_SIZE: usize = 6;
+// 单帧负载上限，防止恶意长度耗尽内存
+pub const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+// 文件分块大小，留出帧内其他字段的空间
+pub const FILE_CHUNK_SIZE: usize = 32 * 1024;
+
+// message type TYPE
+pub const MSG_CHAT: u8 = 0x01;
+pub const MSG_JOIN: u8 = 0x02;
+pub const MSG_LEAVE: u8 = 0x03;
+pub const MSG_ERROR: u8 = 0x04;
+pub const MSG_NICK: u8 = 0x05;
+pub const MSG_WELCOME: u8 = 0x06;
+pub const MSG_RENAMED: u8 = 0x07;
+pub const MSG_LIST: u8 = 0x08;
+pub const MSG_ROOMS: u8 = 0x09;
+pub const MSG_PRIVATE: u8 = 0x0a;
+pub const MSG_HISTORY: u8 = 0x0b;
+pub const MSG_LOGIN: u8 = 0x0c;
+pub const MSG_REGISTER: u8 = 0x0d;
+pub const MSG_FILE_OFFER: u8 = 0x0e;
+pub const MSG_FILE_REPLY: u8 = 0x0f;
+pub const MSG_FILE_CHUNK: u8 = 0x10;
+pub const MSG_FILE_CANCEL: u8 = 0x11;
+pub const MSG_MODERATE: u8 = 0x12;
+pub const MSG_NOTICE: u8 = 0x13;
+pub const MSG_PING: u8 = 0x14;
+pub const MSG_PONG: u8 = 0x15;
+pub const MSG_PRESENCE: u8 = 0x16;
+pub const MSG_TYPING: u8 = 0x17;
+// 以下只在服务器间链接上使用
+pub const MSG_LINK: u8 = 0x18;
+pub const MSG_SERVER: u8 = 0x19;
+pub const MSG_SPLIT: u8 = 0x1a;
+pub const MSG_USER: u8 = 0x1b;
+pub const MSG_QUIT: u8 = 0x1c;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum Frame {
+    /// 聊天消息，room / from / timestamp 由服务端填写 (unix 秒)
+    Chat {
+        #[cfg_attr(feature = "serde", serde(default))]
+        room: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        from: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        timestamp: u64,
+        text: String,
+    },
+    /// 客户端请求加入房间 (who 为空)，或服务端通知有用户加入
+    Join {
+        room: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        who: String,
+    },
+    /// 客户端请求离开当前房间 (字段为空)，或服务端通知有用户离开
+    Leave {
+        #[cfg_attr(feature = "serde", serde(default))]
+        room: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        who: String,
+    },
+    /// 服务端返回的错误
+    Error {
+        message: String,
+    },
+    /// 客户端登录或修改昵称
+    Nick {
+        nick: String,
+    },
+    /// 服务端确认当前昵称
+    Welcome {
+        nick: String,
+    },
+    /// 有用户改名
+    Renamed {
+        old: String,
+        new: String,
+    },
+    /// 客户端请求房间列表
+    List,
+    /// 房间列表及成员
+    Rooms {
+        rooms: Vec<RoomInfo>,
+    },
+    /// 私聊消息，from / timestamp 由服务端填写
+    Private {
+        to: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        from: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        timestamp: u64,
+        text: String,
+    },
+    /// 客户端请求当前房间最近 count 条历史消息，服务端以 Chat 帧逐条回放
+    History {
+        count: u16,
+    },
+    /// 以注册账号登录，成功回复 Welcome，失败回复 Error
+    Login {
+        nick: String,
+        password: String,
+    },
+    /// 注册账号并登录，结果同 Login
+    Register {
+        nick: String,
+        password: String,
+    },
+    // 文件传输经服务端转发，id 由发送方分配；peer 在客户端发出时为对方，
+    // 服务端转发时改写为发出者
+    /// 发送方提出传输请求
+    FileOffer {
+        id: u32,
+        peer: String,
+        name: String,
+        size: u64,
+        #[cfg_attr(feature = "serde", serde(with = "json::hex"))]
+        sha256: [u8; 32],
+    },
+    /// 接收方接受或拒绝
+    FileReply {
+        id: u32,
+        peer: String,
+        accept: bool,
+    },
+    /// 文件数据，按顺序发送，收满 size 字节即结束
+    FileChunk {
+        id: u32,
+        peer: String,
+        #[cfg_attr(feature = "serde", serde(with = "json::base64"))]
+        data: Vec<u8>,
+    },
+    /// 任意一方取消传输
+    FileCancel {
+        id: u32,
+        peer: String,
+        reason: String,
+    },
+    /// 管理操作；room 为空表示当前房间，"*" 表示全服 (仅管理员)，
+    /// duration 为秒数，0 表示永久
+    Moderate {
+        action: ModAction,
+        #[cfg_attr(feature = "serde", serde(default))]
+        room: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        target: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        duration: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        reason: String,
+    },
+    /// 服务端通知，room 不为空时属于该房间
+    Notice {
+        room: String,
+        text: String,
+    },
+    /// 心跳，两端都可以发起，收到的一方以相同 token 回复 Pong
+    Ping {
+        token: u64,
+    },
+    Pong {
+        token: u64,
+    },
+    /// 客户端设置自己的状态 (who 为空)，或服务端通知房间成员的状态变化
+    Presence {
+        #[cfg_attr(feature = "serde", serde(default))]
+        who: String,
+        status: Status,
+    },
+    /// 正在输入提示，客户端发出时 room / who 为空，服务端转发到所在房间
+    Typing {
+        #[cfg_attr(feature = "serde", serde(default))]
+        room: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        who: String,
+        typing: bool,
+    },
+    // 服务器间链接：握手后先互相发送已知的服务器和用户，之后转发各自的变化；
+    // 房间内的事件沿用 Join / Leave / Chat 等帧，who / from 为发出者
+    /// 链接握手，发起方先发，接受方验证口令后以同样的帧回复
+    Link {
+        server: String,
+        password: String,
+    },
+    /// 经这条链接可以到达的服务器
+    Server {
+        name: String,
+    },
+    /// 服务器与网络断开，它上面的用户全部离开
+    Split {
+        server: String,
+    },
+    /// 其他服务器上的用户，room 为空表示不在房间里
+    User {
+        nick: String,
+        server: String,
+        room: String,
+        status: Status,
+    },
+    /// 其他服务器上的用户断开
+    Quit {
+        nick: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ModAction {
+    /// 踢出房间
+    Kick = 1,
+    /// 封禁昵称或 IP，在线的会被踢出
+    Ban = 2,
+    Unban = 3,
+    /// 禁言，仍可以私聊
+    Mute = 4,
+    Unmute = 5,
+    /// 授予或收回房间管理员
+    Op = 6,
+    Deop = 7,
+    /// 列出封禁，结果以 Notice 回复
+    Bans = 8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Status {
+    Online = 1,
+    /// 暂时离开，仍然在线
+    Away = 2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: Vec<String>,
+    /// 成员中的房间管理员
+    pub ops: Vec<String>,
+    /// 成员中暂时离开的
+    pub away: Vec<String>,
+}
+
+impl ModAction {
+    pub fn from_u8(value: u8) -> Option<ModAction> {
+        Some(match value {
+            1 => ModAction::Kick,
+            2 => ModAction::Ban,
+            3 => ModAction::Unban,
+            4 => ModAction::Mute,
+            5 => ModAction::Unmute,
+            6 => ModAction::Op,
+            7 => ModAction::Deop,
+            8 => ModAction::Bans,
+            _ => return None,
+        })
+    }
+}
+
+impl Status {
+    pub fn from_u8(value: u8) -> Option<Status> {
+        match value {
+            1 => Some(Status::Online),
+            2 => Some(Status::Away),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Online => write!(f, "online"),
+            Status::Away => write!(f, "away"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// 对端在帧边界正常关闭连接
+    Closed,
+    Io(io::Error),
+    UnsupportedVersion(u8),
+    /// 未知类型的帧已被完整读出，连接仍然可用
+    UnknownType(u8),
+    TooLarge(usize),
+    InvalidUtf8,
+    Truncated,
+    /// 字段取值不在协议定义的范围内
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Closed => write!(f, "connection closed"),
+            ProtocolError::Io(e) => write!(f, "io error: {}", e),
+            ProtocolError::UnsupportedVersion(v) => {
+                write!(f, "unsupported protocol version: 0x{:02x}", v)
+            }
+            ProtocolError::UnknownType(t) => write!(f, "unknown message type: 0x{:02x}", t),
+            ProtocolError::TooLarge(len) => {
+                write!(f, "frame too large: {} > {}", len, MAX_PAYLOAD_SIZE)
+            }
+            ProtocolError::InvalidUtf8 => write!(f, "invalid utf-8 in message"),
+            ProtocolError::Truncated => write!(f, "truncated frame payload"),
+            ProtocolError::InvalidValue(what) => write!(f, "invalid {} in message", what),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+impl ProtocolError {
+    /// 读到坏帧后是否还能继续读下一帧
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            ProtocolError::UnknownType(_)
+                | ProtocolError::InvalidUtf8
+                | ProtocolError::Truncated
+                | ProtocolError::InvalidValue(_)
+        )
+    }
+}
+
+impl Frame {
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            Frame::Chat { .. } => MSG_CHAT,
+            Frame::Join { .. } => MSG_JOIN,
+            Frame::Leave { .. } => MSG_LEAVE,
+            Frame::Error { .. } => MSG_ERROR,
+            Frame::Nick { .. } => MSG_NICK,
+            Frame::Welcome { .. } => MSG_WELCOME,
+            Frame::Renamed { .. } => MSG_RENAMED,
+            Frame::List => MSG_LIST,
+            Frame::Rooms { .. } => MSG_ROOMS,
+            Frame::Private { .. } => MSG_PRIVATE,
+            Frame::History { .. } => MSG_HISTORY,
+            Frame::Login { .. } => MSG_LOGIN,
+            Frame::Register { .. } => MSG_REGISTER,
+            Frame::FileOffer { .. } => MSG_FILE_OFFER,
+            Frame::FileReply { .. } => MSG_FILE_REPLY,
+            Frame::FileChunk { .. } => MSG_FILE_CHUNK,
+            Frame::FileCancel { .. } => MSG_FILE_CANCEL,
+            Frame::Moderate { .. } => MSG_MODERATE,
+            Frame::Notice { .. } => MSG_NOTICE,
+            Frame::Ping { .. } => MSG_PING,
+            Frame::Pong { .. } => MSG_PONG,
+            Frame::Presence { .. } => MSG_PRESENCE,
+            Frame::Typing { .. } => MSG_TYPING,
+            Frame::Link { .. } => MSG_LINK,
+            Frame::Server { .. } => MSG_SERVER,
+            Frame::Split { .. } => MSG_SPLIT,
+            Frame::User { .. } => MSG_USER,
+            Frame::Quit { .. } => MSG_QUIT,
+        }
+    }
+
+    /// 编码为完整的帧 (含帧头)
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut payload = Vec::new();
+        match self {
+            Frame::Chat {
+                room,
+                from,
+                timestamp,
+                text,
+            } => {
+                put_str(&mut payload, room)?;
+                put_str(&mut payload, from)?;
+                payload.extend_from_slice(&timestamp.to_be_bytes());
+                put_str(&mut payload, text)?;
+            }
+            Frame::Join { room, who } | Frame::Leave { room, who } => {
+                put_str(&mut payload, room)?;
+                put_str(&mut payload, who)?;
+            }
+            Frame::Error { message } => put_str(&mut payload, message)?,
+            Frame::Nick { nick } | Frame::Welcome { nick } => put_str(&mut payload, nick)?,
+            Frame::Renamed { old, new } => {
+                put_str(&mut payload, old)?;
+                put_str(&mut payload, new)?;
+            }
+            Frame::List => {}
+            Frame::Rooms { rooms } => {
+                put_len(&mut payload, rooms.len())?;
+                for room in rooms {
+                    put_str(&mut payload, &room.name)?;
+                    put_len(&mut payload, room.members.len())?;
+                    for member in &room.members {
+                        put_str(&mut payload, member)?;
+                    }
+                    put_len(&mut payload, room.ops.len())?;
+                    for op in &room.ops {
+                        put_str(&mut payload, op)?;
+                    }
+                    put_len(&mut payload, room.away.len())?;
+                    for member in &room.away {
+                        put_str(&mut payload, member)?;
+                    }
+                }
+            }
+            Frame::Private {
+                to,
+                from,
+                timestamp,
+                text,
+            } => {
+                put_str(&mut payload, to)?;
+                put_str(&mut payload, from)?;
+                payload.extend_from_slice(&timestamp.to_be_bytes());
+                put_str(&mut payload, text)?;
+            }
+            Frame::History { count } => payload.extend_from_slice(&count.to_be_bytes()),
+            Frame::Login { nick, password } | Frame::Register { nick, password } => {
+                put_str(&mut payload, nick)?;
+                put_str(&mut payload, password)?;
+            }
+            Frame::FileOffer {
+                id,
+                peer,
+                name,
+                size,
+                sha256,
+            } => {
+                payload.extend_from_slice(&id.to_be_bytes());
+                put_str(&mut payload, peer)?;
+                put_str(&mut payload, name)?;
+                payload.extend_from_slice(&size.to_be_bytes());
+                payload.extend_from_slice(sha256);
+            }
+            Frame::FileReply { id, peer, accept } => {
+                payload.extend_from_slice(&id.to_be_bytes());
+                put_str(&mut payload, peer)?;
+                payload.push(*accept as u8);
+            }
+            Frame::FileChunk { id, peer, data } => {
+                payload.extend_from_slice(&id.to_be_bytes());
+                put_str(&mut payload, peer)?;
+                put_len(&mut payload, data.len())?;
+                payload.extend_from_slice(data);
+            }
+            Frame::FileCancel { id, peer, reason } => {
+                payload.extend_from_slice(&id.to_be_bytes());
+                put_str(&mut payload, peer)?;
+                put_str(&mut payload, reason)?;
+            }
+            Frame::Moderate {
+                action,
+                room,
+                target,
+                duration,
+                reason,
+            } => {
+                payload.push(*action as u8);
+                put_str(&mut payload, room)?;
+                put_str(&mut payload, target)?;
+                payload.extend_from_slice(&duration.to_be_bytes());
+                put_str(&mut payload, reason)?;
+            }
+            Frame::Notice { room, text } => {
+                put_str(&mut payload, room)?;
+                put_str(&mut payload, text)?;
+            }
+            Frame::Ping { token } | Frame::Pong { token } => {
+                payload.extend_from_slice(&token.to_be_bytes())
+            }
+            Frame::Presence { who, status } => {
+                put_str(&mut payload, who)?;
+                payload.push(*status as u8);
+            }
+            Frame::Typing { room, who, typing } => {
+                put_str(&mut payload, room)?;
+                put_str(&mut payload, who)?;
+                payload.push(*typing as u8);
+            }
+            Frame::Link { server, password } => {
+                put_str(&mut payload, server)?;
+                put_str(&mut payload, password)?;
+            }
+            Frame::Server { name } => put_str(&mut payload, name)?,
+            Frame::Split { server } => put_str(&mut payload, server)?,
+            Frame::User {
+                nick,
+                server,
+                room,
+                status,
+            } => {
+                put_str(&mut payload, nick)?;
+                put_str(&mut payload, server)?;
+                put_str(&mut payload, room)?;
+                payload.push(*status as u8);
+            }
+            Frame::Quit { nick } => put_str(&mut payload, nick)?,
+        }
+
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(ProtocolError::TooLarge(payload.len()));
+        }
+
+        let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+        buf.push(PROTOCOL_VERSION);
+        buf.push(self.msg_type());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&payload);
+        Ok(buf)
+    }
+
+    /// 解码帧头之后的负载
+    pub fn decode(msg_type: u8, payload: &[u8]) -> Result<Frame, ProtocolError> {
+        let mut cursor = Cursor { buf: payload };
+        let frame = match msg_type {
+            MSG_CHAT => Frame::Chat {
+                room: cursor.get_str()?,
+                from: cursor.get_str()?,
+                timestamp: cursor.get_u64()?,
+                text: cursor.get_str()?,
+            },
+            MSG_JOIN => Frame::Join {
+                room: cursor.get_str()?,
+                who: cursor.get_str()?,
+            },
+            MSG_LEAVE => Frame::Leave {
+                room: cursor.get_str()?,
+                who: cursor.get_str()?,
+            },
+            MSG_ERROR => Frame::Error {
+                message: cursor.get_str()?,
+            },
+            MSG_NICK => Frame::Nick {
+                nick: cursor.get_str()?,
+            },
+            MSG_WELCOME => Frame::Welcome {
+                nick: cursor.get_str()?,
+            },
+            MSG_RENAMED => Frame::Renamed {
+                old: cursor.get_str()?,
+                new: cursor.get_str()?,
+            },
+            MSG_LIST => Frame::List,
+            MSG_ROOMS => {
+                let mut rooms = Vec::new();
+                for _ in 0..cursor.get_u16()? {
+                    let name = cursor.get_str()?;
+                    let mut members = Vec::new();
+                    for _ in 0..cursor.get_u16()? {
+                        members.push(cursor.get_str()?);
+                    }
+                    let mut ops = Vec::new();
+                    for _ in 0..cursor.get_u16()? {
+                        ops.push(cursor.get_str()?);
+                    }
+                    let mut away = Vec::new();
+                    for _ in 0..cursor.get_u16()? {
+                        away.push(cursor.get_str()?);
+                    }
+                    rooms.push(RoomInfo {
+                        name,
+                        members,
+                        ops,
+                        away,
+                    });
+                }
+                Frame::Rooms { rooms }
+            }
+            MSG_PRIVATE => Frame::Private {
+                to: cursor.get_str()?,
+                from: cursor.get_str()?,
+                timestamp: cursor.get_u64()?,
+                text: cursor.get_str()?,
+            },
+            MSG_HISTORY => Frame::History {
+                count: cursor.get_u16()?,
+            },
+            MSG_LOGIN => Frame::Login {
+                nick: cursor.get_str()?,
+                password: cursor.get_str()?,
+            },
+            MSG_REGISTER => Frame::Register {
+                nick: cursor.get_str()?,
+                password: cursor.get_str()?,
+            },
+            MSG_FILE_OFFER => Frame::FileOffer {
+                id: cursor.get_u32()?,
+                peer: cursor.get_str()?,
+                name: cursor.get_str()?,
+                size: cursor.get_u64()?,
+                sha256: cursor.take(32)?.try_into().unwrap(),
+            },
+            MSG_FILE_REPLY => Frame::FileReply {
+                id: cursor.get_u32()?,
+                peer: cursor.get_str()?,
+                accept: cursor.take(1)?[0] != 0,
+            },
+            MSG_FILE_CHUNK => Frame::FileChunk {
+                id: cursor.get_u32()?,
+                peer: cursor.get_str()?,
+                data: {
+                    let len = cursor.get_u16()? as usize;
+                    cursor.take(len)?.to_vec()
+                },
+            },
+            MSG_FILE_CANCEL => Frame::FileCancel {
+                id: cursor.get_u32()?,
+                peer: cursor.get_str()?,
+                reason: cursor.get_str()?,
+            },
+            MSG_MODERATE => Frame::Moderate {
+                action: ModAction::from_u8(cursor.take(1)?[0])
+                    .ok_or(ProtocolError::InvalidValue("moderation action"))?,
+                room: cursor.get_str()?,
+                target: cursor.get_str()?,
+                duration: cursor.get_u64()?,
+                reason: cursor.get_str()?,
+            },
+            MSG_NOTICE => Frame::Notice {
+                room: cursor.get_str()?,
+                text: cursor.get_str()?,
+            },
+            MSG_PING => Frame::Ping {
+                token: cursor.get_u64()?,
+            },
+            MSG_PONG => Frame::Pong {
+                token: cursor.get_u64()?,
+            },
+            MSG_PRESENCE => Frame::Presence {
+                who: cursor.get_str()?,
+                status: Status::from_u8(cursor.take(1)?[0])
+                    .ok_or(ProtocolError::InvalidValue("presence status"))?,
+            },
+            MSG_TYPING => Frame::Typing {
+                room: cursor.get_str()?,
+                who: cursor.get_str()?,
+                typing: cursor.take(1)?[0] != 0,
+            },
+            MSG_LINK => Frame::Link {
+                server: cursor.get_str()?,
+                password: cursor.get_str()?,
+            },
+            MSG_SERVER => Frame::Server {
+                name: cursor.get_str()?,
+            },
+            MSG_SPLIT => Frame::Split {
+                server: cursor.get_str()?,
+            },
+            MSG_USER => Frame::User {
+                nick: cursor.get_str()?,
+                server: cursor.get_str()?,
+                room: cursor.get_str()?,
+                status: Status::from_u8(cursor.take(1)?[0])
+                    .ok_or(ProtocolError::InvalidValue("user status"))?,
+            },
+            MSG_QUIT => Frame::Quit {
+                nick: cursor.get_str()?,
+            },
+            _ => return Err(ProtocolError::UnknownType(msg_type)),
+        };
+        Ok(frame)
+    }
+}
+
+/// 校验帧头，返回 (TYPE, LENGTH)
+pub fn parse_header(header: &[u8; HEADER_SIZE]) -> Result<(u8, usize), ProtocolError> {
+    if header[0] != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(header[0]));
+    }
+    let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+    if len > MAX_PAYLOAD_SIZE {
+        return Err(ProtocolError::TooLarge(len));
+    }
+    Ok((header[1], len))
+}
+
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Frame, ProtocolError> {
+    let mut header = [0u8; HEADER_SIZE];
+    // 第一个字节单独读，用来区分正常关闭和帧中途断开
+    if reader.read(&mut header[..1])? == 0 {
+        return Err(ProtocolError::Closed);
+    }
+    reader.read_exact(&mut header[1..])?;
+    let (msg_type, len) = parse_header(&header)?;
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Frame::decode(msg_type, &payload)
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, frame: &Frame) -> Result<(), ProtocolError> {
+    writer.write_all(&frame.encode()?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// 字符串长度和列表元素个数都用 u16 表示
+fn put_len(buf: &mut Vec<u8>, len: usize) -> Result<(), ProtocolError> {
+    let len = u16::try_from(len).map_err(|_| ProtocolError::TooLarge(len))?;
+    buf.extend_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) -> Result<(), ProtocolError> {
+    put_len(buf, s.len())?;
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.buf.len() < n {
+            return Err(ProtocolError::Truncated);
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn get_u16(&mut self) -> Result<u16, ProtocolError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn get_u32(&mut self) -> Result<u32, ProtocolError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn get_u64(&mut self) -> Result<u64, ProtocolError> {
+        let bytes = self.take(8)?;
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(bytes);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn get_str(&mut self) -> Result<String, ProtocolError> {
+        let len = self.get_u16()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidUtf8)
+    }
+}
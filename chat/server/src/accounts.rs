@@ -0,0 +1,126 @@
+// 注册账号：每行 "昵称 argon2 哈希"，昵称不含空白，哈希为 PHC 格式
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+
+use crate::state::same_nick;
+
+// 密码长度限制 (字符数)
+const MIN_PASSWORD_LEN: usize = 6;
+const MAX_PASSWORD_LEN: usize = 128;
+
+pub struct Accounts {
+    path: PathBuf,
+    hashes: Mutex<HashMap<String, String>>,
+}
+
+impl Accounts {
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let mut hashes = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                for (n, line) in content.lines().enumerate() {
+                    match line.split_once(' ') {
+                        Some((nick, hash)) => {
+                            hashes.insert(nick.to_string(), hash.to_string());
+                        }
+                        None if line.trim().is_empty() => {}
+                        None => println!("{}:{}: malformed account line", path.display(), n + 1),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(Accounts {
+            path,
+            hashes: Mutex::new(hashes),
+        })
+    }
+
+    /// 不区分大小写，注册了 Alice 后 alice 也不能再用
+    pub fn exists(&self, nick: &str) -> bool {
+        taken(&self.hashes.lock().unwrap(), nick)
+    }
+
+    pub async fn register(&self, nick: &str, password: String) -> Result<(), String> {
+        let chars = password.chars().count();
+        if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&chars) {
+            return Err(format!(
+                "password must be {} to {} characters",
+                MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+            ));
+        }
+        if self.exists(nick) {
+            return Err(format!("{} is already registered", nick));
+        }
+
+        // 哈希计算耗时，不占用异步工作线程
+        let hash = tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+        let mut hashes = self.hashes.lock().unwrap();
+        // 计算哈希期间可能被别人抢先注册
+        if taken(&hashes, nick) {
+            return Err(format!("{} is already registered", nick));
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{} {}", nick, hash))
+            .map_err(|e| {
+                println!("{}: write failed: {}", self.path.display(), e);
+                "failed to save account".to_string()
+            })?;
+        hashes.insert(nick.to_string(), hash);
+        Ok(())
+    }
+
+    /// 验证密码，返回注册时的昵称写法 (登录时不区分大小写)
+    pub async fn verify(&self, nick: &str, password: String) -> Result<String, String> {
+        // 账号不存在和密码错误返回同样的提示
+        let denied = || "invalid nickname or password".to_string();
+        let (name, hash) = find(&self.hashes.lock().unwrap(), nick).ok_or_else(denied)?;
+
+        let valid = tokio::task::spawn_blocking(move || {
+            PasswordHash::new(&hash)
+                .map(|hash| {
+                    Argon2::default()
+                        .verify_password(password.as_bytes(), &hash)
+                        .is_ok()
+                })
+                .unwrap_or(false)
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+
+        if valid { Ok(name) } else { Err(denied()) }
+    }
+}
+
+fn taken(hashes: &HashMap<String, String>, nick: &str) -> bool {
+    find(hashes, nick).is_some()
+}
+
+// 不区分大小写查找账号，返回注册时的昵称和哈希
+fn find(hashes: &HashMap<String, String>, nick: &str) -> Option<(String, String)> {
+    hashes
+        .get_key_value(nick)
+        .or_else(|| hashes.iter().find(|(name, _)| same_nick(name, nick)))
+        .map(|(name, hash)| (name.clone(), hash.clone()))
+}
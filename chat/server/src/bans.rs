@@ -0,0 +1,208 @@
+// 封禁列表：每行 "范围 对象 到期时间 原因"，范围为房间名或 * (全服)，
+// 对象为昵称或 IP，到期时间为 unix 秒，0 表示永久
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use crate::state::{same_nick, validate_nick};
+
+// 全服范围
+pub const SERVER: &str = "*";
+
+#[derive(Debug, Clone)]
+pub enum Target {
+    Nick(String),
+    Ip(IpAddr),
+}
+
+// 昵称不区分大小写，unban bob 能解除对 Bob 的封禁
+impl PartialEq for Target {
+    fn eq(&self, other: &Target) -> bool {
+        match (self, other) {
+            (Target::Nick(a), Target::Nick(b)) => same_nick(a, b),
+            (Target::Ip(a), Target::Ip(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Target {
+    /// 能解析成 IP 的按 IP 封禁，否则必须是合法的昵称
+    pub fn parse(target: &str) -> Result<Target, String> {
+        match target.parse::<IpAddr>() {
+            Ok(ip) => Ok(Target::Ip(ip.to_canonical())),
+            Err(_) => validate_nick(target).map(|()| Target::Nick(target.to_string())),
+        }
+    }
+
+    pub fn matches(&self, nick: Option<&str>, ip: IpAddr) -> bool {
+        match self {
+            Target::Nick(banned) => nick.is_some_and(|nick| same_nick(nick, banned)),
+            // 监听 IPv6 时 IPv4 客户端的地址形如 ::ffff:1.2.3.4
+            Target::Ip(banned) => *banned == ip.to_canonical(),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Nick(nick) => write!(f, "{}", nick),
+            Target::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+pub struct Ban {
+    pub scope: String,
+    pub target: Target,
+    pub expires: u64,
+    pub reason: String,
+}
+
+impl Ban {
+    pub fn describe(&self, now: u64) -> String {
+        describe(&self.reason, self.expires, now)
+    }
+}
+
+/// 给用户看的说明，如 "spam, 9m 59s left"
+pub fn describe(reason: &str, expires: u64, now: u64) -> String {
+    let reason = match reason {
+        "" => "no reason given",
+        reason => reason,
+    };
+    match expires {
+        0 => format!("{}, permanent", reason),
+        expires => format!(
+            "{}, {} left",
+            reason,
+            format_duration(expires.saturating_sub(now))
+        ),
+    }
+}
+
+pub struct Bans {
+    path: PathBuf,
+    entries: Vec<Ban>,
+}
+
+impl Bans {
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                for (n, line) in content.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match parse_line(line) {
+                        Some(ban) => entries.push(ban),
+                        None => println!("{}:{}: malformed ban line", path.display(), n + 1),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Bans { path, entries })
+    }
+
+    /// 添加封禁，同一范围同一对象的旧记录被替换
+    pub fn add(&mut self, ban: Ban) -> Result<(), String> {
+        format_line(&ban)?;
+        self.entries
+            .retain(|b| !(b.scope == ban.scope && b.target == ban.target));
+        self.entries.push(ban);
+        self.save()
+    }
+
+    pub fn remove(&mut self, scope: &str, target: &Target) -> Result<(), String> {
+        let before = self.entries.len();
+        self.entries
+            .retain(|b| !(b.scope == scope && b.target == *target));
+        if self.entries.len() == before {
+            return Err(format!("{} is not banned", target));
+        }
+        self.save()
+    }
+
+    /// 查找对该用户生效的封禁
+    pub fn find(&mut self, scope: &str, nick: Option<&str>, ip: IpAddr, now: u64) -> Option<&Ban> {
+        self.expire(now);
+        self.entries
+            .iter()
+            .find(|b| b.scope == scope && b.target.matches(nick, ip))
+    }
+
+    pub fn list(&mut self, scope: &str, now: u64) -> Vec<&Ban> {
+        self.expire(now);
+        self.entries.iter().filter(|b| b.scope == scope).collect()
+    }
+
+    // 到期的记录只在内存中删除，下次保存时一并写回
+    fn expire(&mut self, now: u64) {
+        self.entries.retain(|b| b.expires == 0 || b.expires > now);
+    }
+
+    // 先写临时文件再改名，写到一半崩溃也不会丢掉整个列表
+    fn save(&self) -> Result<(), String> {
+        let content = self
+            .entries
+            .iter()
+            .map(format_line)
+            .collect::<Result<String, String>>()?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content)
+            .and_then(|()| fs::rename(&tmp, &self.path))
+            .map_err(|e| {
+                println!("{}: write failed: {}", self.path.display(), e);
+                "failed to save ban list".to_string()
+            })
+    }
+}
+
+// 字段里的空白或换行会让这一行被读成别的封禁，拒绝保存
+fn format_line(ban: &Ban) -> Result<String, String> {
+    let field = |s: &str| !s.is_empty() && !s.chars().any(|c| c.is_whitespace() || c.is_control());
+    let target = ban.target.to_string();
+    if !field(&ban.scope) || !field(&target) || ban.reason.chars().any(char::is_control) {
+        return Err(format!("invalid ban on {}", target));
+    }
+    Ok(format!(
+        "{} {} {} {}\n",
+        ban.scope, target, ban.expires, ban.reason
+    ))
+}
+
+fn parse_line(line: &str) -> Option<Ban> {
+    let mut fields = line.splitn(4, ' ');
+    let scope = fields.next()?.to_string();
+    let target = Target::parse(fields.next()?).ok()?;
+    let expires = fields.next()?.parse().ok()?;
+    let reason = fields.next().unwrap_or("").to_string();
+    Some(Ban {
+        scope,
+        target,
+        expires,
+        reason,
+    })
+}
+
+/// 秒数转成 "1d 2h"、"10m 5s" 这样的形式，只保留最大的两个单位
+pub fn format_duration(mut secs: u64) -> String {
+    let mut parts = Vec::new();
+    for (unit, size) in [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+        if secs >= size {
+            parts.push(format!("{}{}", secs / size, unit));
+            secs %= size;
+        }
+    }
+    if parts.is_empty() {
+        return "0s".to_string();
+    }
+    parts.truncate(2);
+    parts.join(" ")
+}
@@ -0,0 +1,498 @@
+// 压测工具：在本机启动 N 个模拟客户端，按设定速率向房间发消息，
+// 统计吞吐量、广播延迟分位数和丢失的消息
+//
+// 不指定 --server 时在进程内启动服务端 (不记录历史、不限流)，可以直接放进 CI：
+// cargo run --release -p server --bin bench -- --clients 200 --max-p99-ms 50 --max-drop-rate 0
+// 压测外部服务端时，每个客户端的速率须低于服务端的 --rate-limit
+use clap::Parser;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Barrier, Semaphore, mpsc, watch};
+use tokio::time::{Instant, interval_at, sleep, sleep_until, timeout};
+
+use protocol::async_io::{read_frame, write_frame};
+use protocol::{Frame, MAX_PAYLOAD_SIZE};
+use server::accept_loop;
+use server::accounts::Accounts;
+use server::bans::Bans;
+use server::history::History;
+use server::limit::Limits;
+use server::state::{Options, ServerState};
+
+// 连接、登录和加入房间的总时限
+const SETUP_TIMEOUT: Duration = Duration::from_secs(30);
+// 等待发送结束和剩余消息时的检查间隔
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// 压测已在运行的服务端 (host:port)，不指定则在进程内启动一个
+    #[arg(short, long)]
+    server: Option<String>,
+
+    /// 模拟客户端数
+    #[arg(short, long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+    clients: u32,
+
+    /// 房间数，客户端轮流分到各个房间
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    rooms: u32,
+
+    /// 每个客户端每秒发送的消息数
+    #[arg(short, long, default_value_t = 1.0)]
+    rate: f64,
+
+    /// 发送持续的秒数
+    #[arg(short, long, default_value_t = 10)]
+    duration: u64,
+
+    /// 每条消息的长度 (字节)
+    #[arg(long, default_value_t = 64)]
+    size: usize,
+
+    /// 停止发送后最多等待剩余消息多少秒
+    #[arg(long, default_value_t = 5)]
+    drain: u64,
+
+    /// 昵称和房间名前缀，压测外部服务端时避免和真实用户冲突
+    #[arg(long, default_value = "bench")]
+    prefix: String,
+
+    /// p99 延迟超过这么多毫秒时以失败退出
+    #[arg(long)]
+    max_p99_ms: Option<f64>,
+
+    /// 丢失的消息比例 (0 到 1) 超过该值时以失败退出
+    #[arg(long)]
+    max_drop_rate: Option<f64>,
+}
+
+// 所有客户端共享的时钟和计数，主任务据此判断何时结束
+struct Shared {
+    // 消息里的发送时间以它为起点
+    epoch: Instant,
+    // 每个房间发出的消息数
+    sent: Vec<AtomicU64>,
+    // 已停止发送的客户端数
+    done: AtomicUsize,
+    received: AtomicU64,
+}
+
+struct Client {
+    nick: String,
+    room: String,
+    room_index: usize,
+    // 第一条消息相对开始时间的偏移，错开各客户端的发送时刻
+    offset: Duration,
+    period: Duration,
+    size: usize,
+    shared: Arc<Shared>,
+}
+
+// 每个客户端收到的消息，结束后汇总
+#[derive(Default)]
+struct Received {
+    count: u64,
+    // 服务端报告的因读得太慢而丢弃的消息数
+    lagged: u64,
+    // 从发出到收到的时间 (微秒)
+    latencies: Vec<u64>,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    if !(args.rate > 0.0 && args.rate.is_finite()) {
+        fail("--rate must be a positive number".to_string());
+    }
+    if args.size > MAX_PAYLOAD_SIZE / 2 {
+        fail(format!(
+            "--size must be at most {} bytes",
+            MAX_PAYLOAD_SIZE / 2
+        ));
+    }
+
+    let addr = match &args.server {
+        Some(server) => match tokio::net::lookup_host(server.as_str()).await {
+            Ok(mut addrs) => match addrs.next() {
+                Some(addr) => addr,
+                None => fail(format!("{}: no address found", server)),
+            },
+            Err(e) => fail(format!("{}: {}", server, e)),
+        },
+        None => match start_server(args.clients).await {
+            Ok(addr) => addr,
+            Err(e) => fail(format!("failed to start server: {}", e)),
+        },
+    };
+    println!(
+        "bench: {} clients in {} room(s) on {}, {} msg/s each for {}s, {} bytes per message",
+        args.clients, args.rooms, addr, args.rate, args.duration, args.size
+    );
+
+    let clients = args.clients as usize;
+    let rooms = args.rooms as usize;
+    let shared = Arc::new(Shared {
+        epoch: Instant::now(),
+        sent: (0..rooms).map(|_| AtomicU64::new(0)).collect(),
+        done: AtomicUsize::new(0),
+        received: AtomicU64::new(0),
+    });
+    // 所有客户端进入房间后再一起开始发送
+    let ready = Arc::new(Barrier::new(clients + 1));
+    let (start_tx, start) = watch::channel(None);
+    let (stop_tx, stop) = watch::channel(false);
+    let period = Duration::from_secs_f64(1.0 / args.rate);
+
+    let mut tasks = Vec::with_capacity(clients);
+    for id in 0..clients {
+        let client = Client {
+            nick: format!("{}{}", args.prefix, id),
+            room: format!("#{}{}", args.prefix, id % rooms),
+            room_index: id % rooms,
+            offset: period.mul_f64(id as f64 / clients as f64),
+            period,
+            size: args.size,
+            shared: shared.clone(),
+        };
+        let run = client.run(addr, ready.clone(), start.clone(), stop.clone());
+        tasks.push(tokio::spawn(run));
+    }
+
+    let setup = Instant::now();
+    if timeout(SETUP_TIMEOUT, ready.wait()).await.is_err() {
+        // 报告第一个失败的客户端
+        for task in tasks.into_iter().filter(|task| task.is_finished()) {
+            if let Ok(Err(e)) = task.await {
+                fail(e);
+            }
+        }
+        fail(format!(
+            "clients not ready after {}s",
+            SETUP_TIMEOUT.as_secs()
+        ));
+    }
+    println!(
+        "{} clients connected in {} ms",
+        clients,
+        setup.elapsed().as_millis()
+    );
+
+    let begin = Instant::now();
+    let deadline = begin + Duration::from_secs(args.duration);
+    let _ = start_tx.send(Some((begin, deadline)));
+    sleep_until(deadline).await;
+
+    // 等所有客户端停止发送后才能算出应收条数，再等剩余消息到达
+    let drain_deadline = deadline + Duration::from_secs(args.drain);
+    while shared.done.load(Ordering::Relaxed) < clients && Instant::now() < drain_deadline {
+        sleep(DRAIN_POLL).await;
+    }
+    // 发送者也在房间里，每条消息应送达房间里的每个成员
+    let expected: u64 = (0..rooms)
+        .map(|room| {
+            let members = (0..clients).filter(|id| id % rooms == room).count() as u64;
+            shared.sent[room].load(Ordering::Relaxed) * members
+        })
+        .sum();
+    while shared.received.load(Ordering::Relaxed) < expected && Instant::now() < drain_deadline {
+        sleep(DRAIN_POLL).await;
+    }
+    let finished = begin.elapsed();
+    let _ = stop_tx.send(true);
+
+    let mut total = Received::default();
+    for task in tasks {
+        match task.await {
+            Ok(Ok(received)) => {
+                total.count += received.count;
+                total.lagged += received.lagged;
+                total.latencies.extend(received.latencies);
+            }
+            Ok(Err(e)) => println!("! {}", e),
+            Err(e) => println!("! client task failed: {}", e),
+        }
+    }
+    let sent: u64 = shared.sent.iter().map(|n| n.load(Ordering::Relaxed)).sum();
+    if !report(&args, sent, expected, &mut total, finished) {
+        std::process::exit(1);
+    }
+}
+
+impl Client {
+    async fn run(
+        self,
+        addr: SocketAddr,
+        ready: Arc<Barrier>,
+        mut start: watch::Receiver<Option<(Instant, Instant)>>,
+        mut stop: watch::Receiver<bool>,
+    ) -> Result<Received, String> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| format!("{}: connect failed: {}", self.nick, e))?;
+        let _ = stream.set_nodelay(true);
+        let (mut reader, mut writer) = stream.into_split();
+
+        // 登录后会自动进入默认房间，再切换到压测房间
+        let hello = Frame::Nick {
+            nick: self.nick.clone(),
+        };
+        write_frame(&mut writer, &hello)
+            .await
+            .map_err(|e| self.error(e))?;
+        let join = Frame::Join {
+            room: self.room.clone(),
+            who: String::new(),
+        };
+        write_frame(&mut writer, &join)
+            .await
+            .map_err(|e| self.error(e))?;
+        loop {
+            match read_frame(&mut reader).await.map_err(|e| self.error(e))? {
+                Frame::Join { room, who } if room == self.room && who == self.nick => break,
+                Frame::Error { message } => return Err(self.error(message)),
+                Frame::Ping { token } => {
+                    let pong = Frame::Pong { token };
+                    write_frame(&mut writer, &pong)
+                        .await
+                        .map_err(|e| self.error(e))?;
+                }
+                _ => {}
+            }
+        }
+        ready.wait().await;
+
+        // 心跳由接收任务收到，交给这里回复
+        let (pong_tx, mut pongs) = mpsc::channel(4);
+        let receiver = tokio::spawn(receive(
+            reader,
+            self.room.clone(),
+            self.shared.clone(),
+            pong_tx,
+            stop.clone(),
+        ));
+
+        let (begin, deadline) = match start.wait_for(Option::is_some).await {
+            Ok(times) => times.unwrap(),
+            Err(_) => return Err(self.error("bench aborted")),
+        };
+        let mut tick = interval_at(begin + self.offset, self.period);
+        let sending = loop {
+            tokio::select! {
+                now = tick.tick() => {
+                    if now >= deadline {
+                        break Ok(());
+                    }
+                    let chat = Frame::Chat {
+                        room: String::new(),
+                        from: String::new(),
+                        timestamp: 0,
+                        text: self.message(),
+                    };
+                    if let Err(e) = write_frame(&mut writer, &chat).await {
+                        break Err(self.error(e));
+                    }
+                    self.shared.sent[self.room_index].fetch_add(1, Ordering::Relaxed);
+                }
+                Some(token) = pongs.recv() => {
+                    let _ = write_frame(&mut writer, &Frame::Pong { token }).await;
+                }
+            }
+        };
+        self.shared.done.fetch_add(1, Ordering::Relaxed);
+
+        // 写端保持打开直到压测结束，否则服务端会当作断开，发给自己的消息就丢了
+        loop {
+            tokio::select! {
+                Some(token) = pongs.recv() => {
+                    let _ = write_frame(&mut writer, &Frame::Pong { token }).await;
+                }
+                _ = stop.changed() => break,
+            }
+        }
+        drop(writer);
+        sending?;
+        receiver
+            .await
+            .map_err(|e| format!("{}: receiver failed: {}", self.nick, e))
+    }
+
+    fn error(&self, e: impl fmt::Display) -> String {
+        format!("{}: {}", self.nick, e)
+    }
+
+    // 以发送时间 (微秒) 开头，补齐到指定长度
+    fn message(&self) -> String {
+        let micros = self.shared.epoch.elapsed().as_micros();
+        let mut text = format!("{} ", micros);
+        let pad = self.size.saturating_sub(text.len());
+        text.extend(std::iter::repeat_n('x', pad));
+        text
+    }
+}
+
+async fn receive(
+    mut reader: OwnedReadHalf,
+    room: String,
+    shared: Arc<Shared>,
+    pongs: mpsc::Sender<u64>,
+    mut stop: watch::Receiver<bool>,
+) -> Received {
+    let mut received = Received::default();
+    loop {
+        // read_frame 不能安全取消，但取消时压测已经结束，连接随后关闭
+        let frame = tokio::select! {
+            frame = read_frame(&mut reader) => frame,
+            _ = stop.changed() => break,
+        };
+        match frame {
+            Ok(Frame::Chat {
+                room: from_room,
+                text,
+                ..
+            }) if from_room == room => {
+                let Some(sent) = text.split(' ').next().and_then(|t| t.parse::<u64>().ok()) else {
+                    continue;
+                };
+                let now = shared.epoch.elapsed().as_micros() as u64;
+                received.latencies.push(now.saturating_sub(sent));
+                received.count += 1;
+                shared.received.fetch_add(1, Ordering::Relaxed);
+            }
+            // 服务端的提示形如 "too slow, 12 messages dropped"
+            Ok(Frame::Error { message }) => {
+                if let Some(n) = message
+                    .strip_prefix("too slow, ")
+                    .and_then(|rest| rest.split(' ').next())
+                    .and_then(|n| n.parse::<u64>().ok())
+                {
+                    received.lagged += n;
+                }
+            }
+            Ok(Frame::Ping { token }) => {
+                let _ = pongs.send(token).await;
+            }
+            Ok(_) => {}
+            Err(e) if e.is_recoverable() => {}
+            Err(_) => break,
+        }
+    }
+    received
+}
+
+// 进程内服务端：只监听本机，不记录历史，账号和封禁文件不存在也不会写入
+async fn start_server(clients: u32) -> Result<SocketAddr, String> {
+    server::set_quiet(true);
+    let scratch = std::env::temp_dir().join(format!("chat-bench-{}", std::process::id()));
+    let history = History::open(scratch.join("history"), 0).map_err(|e| e.to_string())?;
+    let accounts = Accounts::load(scratch.join("accounts")).map_err(|e| e.to_string())?;
+    let bans = Bans::load(scratch.join("bans")).map_err(|e| e.to_string())?;
+    let options = Options {
+        name: "bench".to_string(),
+        replay: 0,
+        require_login: false,
+        max_file_size: 0,
+        admins: HashSet::new(),
+        ping_interval: Duration::from_secs(30),
+        ping_timeout: Duration::from_secs(90),
+        // 压测本身就是在刷消息，不限流
+        limits: Limits {
+            rate: 0.0,
+            burst: 1,
+            strikes: 0,
+            mute: Duration::ZERO,
+        },
+        max_message_size: MAX_PAYLOAD_SIZE,
+    };
+    let state = Arc::new(ServerState::new(history, accounts, bans, options));
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .map_err(|e| e.to_string())?;
+    let addr = listener.local_addr().map_err(|e| e.to_string())?;
+    let limit = Arc::new(Semaphore::new(clients as usize));
+    tokio::spawn(accept_loop(listener, None, state, limit, false));
+    Ok(addr)
+}
+
+// 打印结果，超过阈值时返回 false
+fn report(
+    args: &Args,
+    sent: u64,
+    expected: u64,
+    received: &mut Received,
+    elapsed: Duration,
+) -> bool {
+    let secs = args.duration as f64;
+    let dropped = expected.saturating_sub(received.count);
+    let drop_rate = match expected {
+        0 => 0.0,
+        expected => dropped as f64 / expected as f64,
+    };
+    println!(
+        "sent       {} messages ({:.1} msg/s)",
+        sent,
+        sent as f64 / secs
+    );
+    println!(
+        "delivered  {} of {} ({:.1} msg/s over {:.1}s)",
+        received.count,
+        expected,
+        received.count as f64 / elapsed.as_secs_f64(),
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "dropped    {} ({:.2}%), server reported {} lagged",
+        dropped,
+        drop_rate * 100.0,
+        received.lagged
+    );
+
+    received.latencies.sort_unstable();
+    let percentile = |p: f64| -> f64 {
+        let latencies = &received.latencies;
+        let index = ((latencies.len() as f64 * p).ceil() as usize).saturating_sub(1);
+        latencies
+            .get(index)
+            .map_or(0.0, |&micros| micros as f64 / 1000.0)
+    };
+    let p99 = percentile(0.99);
+    println!(
+        "latency    p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, max {:.2} ms",
+        percentile(0.50),
+        percentile(0.90),
+        p99,
+        percentile(1.0)
+    );
+
+    let mut ok = true;
+    if sent == 0 {
+        println!("FAILED: no messages were sent");
+        ok = false;
+    }
+    if let Some(max) = args.max_p99_ms
+        && p99 > max
+    {
+        println!("FAILED: p99 latency {:.2} ms > {} ms", p99, max);
+        ok = false;
+    }
+    if let Some(max) = args.max_drop_rate
+        && drop_rate > max
+    {
+        println!("FAILED: drop rate {:.4} > {}", drop_rate, max);
+        ok = false;
+    }
+    ok
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("bench: {}", message);
+    std::process::exit(1)
+}
@@ -0,0 +1,483 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast, mpsc};
+use tokio::time::{Instant, MissedTickBehavior, interval_at, timeout};
+
+use protocol::async_io::{read_frame, write_frame};
+use protocol::{Frame, ProtocolError};
+
+use crate::bans::format_duration;
+use crate::limit::{Check, Limiter};
+use crate::state::{DEFAULT_ROOM, Joined, Moderation, ServerState, same_nick, validate_nick};
+
+// 单帧写超时，客户端长时间不读时断开，避免占着连接
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// 发给单个客户端的私有消息 (错误提示等) 最多排队的条数
+const DIRECT_CAPACITY: usize = 64;
+const LOGIN_REQUIRED: &str =
+    "login required: /login <nick> <password> or /register <nick> <password>";
+
+/// 发给写任务的指令
+pub enum Outbound {
+    /// 只发给该客户端的帧
+    Frame(Arc<Frame>),
+    /// 切换订阅的房间，None 表示不在任何房间
+    Room(Option<broadcast::Receiver<Arc<Frame>>>),
+    /// 发完之前的消息后断开连接
+    Close,
+}
+
+// 排队中的指令，普通消息占着一个名额，写出后归还
+type Queued = (Outbound, Option<OwnedSemaphorePermit>);
+
+/// 发给单个客户端的队列。普通消息最多排 DIRECT_CAPACITY 条，
+/// 控制指令 (移出房间、断开) 不占名额，队列满时也不会丢，和普通消息保持先后顺序
+#[derive(Clone)]
+pub struct Direct {
+    tx: mpsc::UnboundedSender<Queued>,
+    slots: Arc<Semaphore>,
+}
+
+impl Direct {
+    fn new() -> (Direct, mpsc::UnboundedReceiver<Queued>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let slots = Arc::new(Semaphore::new(DIRECT_CAPACITY));
+        (Direct { tx, slots }, rx)
+    }
+
+    /// 等到队列有空位再发送，慢客户端会反压到发送方
+    pub async fn send(&self, out: Outbound) -> Result<(), Outbound> {
+        // 连接断开后队列里的消息随之释放，不会一直等下去
+        let permit = self.slots.clone().acquire_owned().await.ok();
+        self.tx.send((out, permit)).map_err(|e| e.0.0)
+    }
+
+    pub fn try_send(&self, out: Outbound) -> Result<(), TrySendError<Outbound>> {
+        if self.tx.is_closed() {
+            return Err(TrySendError::Closed(out));
+        }
+        match self.slots.clone().try_acquire_owned() {
+            Ok(permit) => self
+                .tx
+                .send((out, Some(permit)))
+                .map_err(|e| TrySendError::Closed(e.0.0)),
+            Err(_) => Err(TrySendError::Full(out)),
+        }
+    }
+
+    /// 发送控制指令，不受队列长度限制
+    pub fn control(&self, out: Outbound) {
+        let _ = self.tx.send((out, None));
+    }
+
+    pub fn same_channel(&self, other: &Direct) -> bool {
+        self.tx.same_channel(&other.tx)
+    }
+}
+
+pub async fn handle<S>(socket: S, addr: SocketAddr, state: Arc<ServerState>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, writer) = tokio::io::split(socket);
+    let (direct_tx, direct_rx) = Direct::new();
+
+    let ping_interval = state.options.ping_interval;
+    let mut writer_task = tokio::spawn(write_loop(writer, direct_rx, ping_interval));
+    let mut client = Client {
+        nick: None,
+        account: false,
+        ip: addr.ip(),
+        limiter: Limiter::new(state.options.limits),
+    };
+
+    let read_loop = async {
+        loop {
+            // 客户端按时回复心跳，超时未收到任何消息说明连接已经失效
+            let frame = match timeout(state.options.ping_timeout, read_frame(&mut reader)).await {
+                Ok(frame) => frame,
+                Err(_) => {
+                    println!("{}: ping timeout", addr);
+                    break;
+                }
+            };
+            let reply = match frame {
+                Ok(frame) => match admit(&mut client, &frame, addr) {
+                    Ok(()) => match on_frame(frame, &mut client, &state, &direct_tx).await {
+                        Ok(Some(reply)) => reply,
+                        Ok(None) => continue,
+                        Err(message) => Frame::Error { message },
+                    },
+                    Err(Some(reply)) => reply,
+                    Err(None) => continue,
+                },
+                Err(e) if e.is_recoverable() => {
+                    println!("{}: bad frame: {}", addr, e);
+                    Frame::Error {
+                        message: e.to_string(),
+                    }
+                }
+                Err(ProtocolError::Closed) => break,
+                Err(e) => {
+                    println!("{}: {}", addr, e);
+                    break;
+                }
+            };
+
+            if direct_tx
+                .send(Outbound::Frame(Arc::new(reply)))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    };
+
+    // 任意一边结束 (客户端断开或写失败) 都关闭连接
+    tokio::select! {
+        _ = read_loop => writer_task.abort(),
+        _ = &mut writer_task => {}
+    }
+
+    if let Some(nick) = client.nick {
+        log!("{}: {} logged out", addr, nick);
+        state.release(&nick, &direct_tx);
+    }
+}
+
+struct Client {
+    // 登录前为 None，只接受 Nick / Login / Register 帧
+    nick: Option<String>,
+    // 是否以注册账号登录
+    account: bool,
+    ip: IpAddr,
+    limiter: Limiter,
+}
+
+// 限流检查，Err 表示丢弃这条消息，附带要回复给客户端的帧
+fn admit(client: &mut Client, frame: &Frame, addr: SocketAddr) -> Result<(), Option<Frame>> {
+    // 心跳和文件数据不限流，文件数据受接收方反压和大小上限约束
+    let speech = match frame {
+        Frame::Ping { .. }
+        | Frame::Pong { .. }
+        | Frame::FileReply { .. }
+        | Frame::FileChunk { .. }
+        | Frame::FileCancel { .. } => return Ok(()),
+        Frame::Chat { .. } | Frame::Private { .. } | Frame::Typing { .. } => true,
+        _ => false,
+    };
+    let notice = |text| Frame::Notice {
+        room: String::new(),
+        text,
+    };
+    match client.limiter.check(speech) {
+        Check::Pass => Ok(()),
+        Check::Drop(text) => Err(text.map(notice)),
+        Check::Mute(text) => {
+            let nick = client.nick.as_deref().unwrap_or("guest");
+            println!("{}: {} muted for flooding", addr, nick);
+            Err(Some(notice(text)))
+        }
+        Check::Muted(_) if matches!(frame, Frame::Typing { .. }) => Err(None),
+        Check::Muted(left) => Err(Some(Frame::Error {
+            message: format!(
+                "you are muted for flooding: {} left",
+                format_duration(left.as_secs_f64().ceil() as u64)
+            ),
+        })),
+    }
+}
+
+// 处理一个客户端帧，返回要回给该客户端的帧，Err 作为错误帧回复
+async fn on_frame(
+    frame: Frame,
+    client: &mut Client,
+    state: &ServerState,
+    direct: &Direct,
+) -> Result<Option<Frame>, String> {
+    match frame {
+        Frame::Ping { token } => return Ok(Some(Frame::Pong { token })),
+        Frame::Pong { .. } => return Ok(None),
+        Frame::Nick { nick: wanted } => {
+            if state.options.require_login && !client.account {
+                return Err(LOGIN_REQUIRED.to_string());
+            }
+            if client.account {
+                return Err("cannot change nickname while logged in to an account".to_string());
+            }
+            state.check_reserved(&wanted)?;
+            if state.accounts.exists(&wanted) {
+                return Err(format!(
+                    "{} is registered, use /login {} <password>",
+                    wanted, wanted
+                ));
+            }
+            return set_nick(client, wanted, state, direct).await.map(Some);
+        }
+        Frame::Login {
+            nick: wanted,
+            password,
+        } => {
+            if client.account {
+                return Err("already logged in".to_string());
+            }
+            let wanted = state.accounts.verify(&wanted, password).await?;
+            let reply = set_nick(client, wanted, state, direct).await?;
+            client.account = true;
+            return Ok(Some(reply));
+        }
+        Frame::Register {
+            nick: wanted,
+            password,
+        } => {
+            if client.account {
+                return Err("already logged in".to_string());
+            }
+            // 游客可以注册自己正在用的昵称，按正在用的写法注册
+            let own = client
+                .nick
+                .as_deref()
+                .is_some_and(|nick| same_nick(nick, &wanted));
+            let wanted = match own {
+                true => client.nick.clone().unwrap_or(wanted),
+                false => wanted,
+            };
+            validate_nick(&wanted)?;
+            state.check_reserved(&wanted)?;
+            if !own && state.is_online(&wanted) {
+                return Err(format!("nickname {} is already taken", wanted));
+            }
+            state.accounts.register(&wanted, password).await?;
+            println!("{} registered", wanted);
+            let reply = match own {
+                true => Frame::Welcome { nick: wanted },
+                false => set_nick(client, wanted, state, direct).await?,
+            };
+            client.account = true;
+            return Ok(Some(reply));
+        }
+        _ => {}
+    }
+
+    let nick = match &client.nick {
+        Some(nick) => nick.as_str(),
+        None if state.options.require_login => return Err(LOGIN_REQUIRED.to_string()),
+        None => return Err("choose a nickname first".to_string()),
+    };
+    match frame {
+        Frame::Chat { text, .. } => {
+            check_size(&text, state)?;
+            state.say(nick, direct, text)?;
+            Ok(None)
+        }
+        Frame::Join { room, .. } => {
+            let joined = state.join(nick, direct, &room).await?;
+            log!("{} joined {}", nick, joined.room);
+            switch_room(direct, joined).await;
+            Ok(None)
+        }
+        Frame::Leave { .. } => {
+            state.leave(nick, direct)?;
+            let _ = direct.send(Outbound::Room(None)).await;
+            Ok(None)
+        }
+        Frame::Private { to, text, .. } => {
+            check_size(&text, state)?;
+            state.whisper(nick, direct, &to, text).map(Some)
+        }
+        Frame::History { count } => {
+            for frame in state.history(nick, direct, count as usize)? {
+                let _ = direct.send(Outbound::Frame(frame)).await;
+            }
+            Ok(None)
+        }
+        Frame::FileOffer {
+            id,
+            peer,
+            name,
+            size,
+            sha256,
+        } => {
+            let offer = Frame::FileOffer {
+                id,
+                peer: nick.to_string(),
+                name,
+                size,
+                sha256,
+            };
+            // 失败时以取消回复，发送方据此清理这次传输
+            if let Err(reason) = state.offer_file(nick, direct, &peer, offer) {
+                return Ok(Some(Frame::FileCancel { id, peer, reason }));
+            }
+            println!("{} offers file {} to {} ({} bytes)", nick, id, peer, size);
+            Ok(None)
+        }
+        Frame::FileReply { id, peer, accept } => {
+            state.reply_file(nick, direct, &peer, id, accept)?;
+            Ok(None)
+        }
+        Frame::FileChunk { id, peer: _, data } => {
+            // 取消后仍在路上的数据块直接丢弃
+            let Some(to) = state.file_chunk(nick, direct, id, data.len())? else {
+                return Ok(None);
+            };
+            // 等待接收方队列有空位，慢速接收方会反压到发送方
+            let chunk = Frame::FileChunk {
+                id,
+                peer: nick.to_string(),
+                data,
+            };
+            let _ = to.send(Outbound::Frame(Arc::new(chunk))).await;
+            Ok(None)
+        }
+        Frame::FileCancel { id, peer, reason } => {
+            state.cancel_file(nick, direct, &peer, id, reason)?;
+            Ok(None)
+        }
+        Frame::Moderate {
+            action,
+            room,
+            target,
+            duration,
+            reason,
+        } => {
+            let request = Moderation {
+                action,
+                room: &room,
+                target: &target,
+                duration,
+                reason: &reason,
+            };
+            for reply in state.moderate(nick, direct, request)? {
+                let _ = direct.send(Outbound::Frame(Arc::new(reply))).await;
+            }
+            Ok(None)
+        }
+        Frame::Presence { status, .. } => state.presence(nick, direct, status),
+        Frame::Typing { typing, .. } => {
+            state.typing(nick, direct, typing);
+            Ok(None)
+        }
+        Frame::List => Ok(Some(Frame::Rooms {
+            rooms: state.list(),
+        })),
+        frame => {
+            println!("{}: unexpected frame {:?}", nick, frame);
+            Ok(None)
+        }
+    }
+}
+
+fn check_size(text: &str, state: &ServerState) -> Result<(), String> {
+    let max = state.options.max_message_size;
+    match text.len() > max {
+        true => Err(format!("message too long: {} > {} bytes", text.len(), max)),
+        false => Ok(()),
+    }
+}
+
+// 占用昵称：第一次设置时加入默认房间，之后为改名
+async fn set_nick(
+    client: &mut Client,
+    wanted: String,
+    state: &ServerState,
+    direct: &Direct,
+) -> Result<Frame, String> {
+    state.claim(&wanted, client.nick.as_deref(), direct, client.ip)?;
+    match client.nick.replace(wanted.clone()) {
+        Some(old) => log!("{} is now known as {}", old, wanted),
+        None => {
+            log!("{} logged in", wanted);
+            let joined = state.join(&wanted, direct, DEFAULT_ROOM).await?;
+            switch_room(direct, joined).await;
+        }
+    }
+    Ok(Frame::Welcome { nick: wanted })
+}
+
+// 先回放历史再切换订阅，新房间的实时消息已在订阅中缓存，排在历史之后
+async fn switch_room(direct: &Direct, joined: Joined) {
+    for frame in joined.replay {
+        let _ = direct.send(Outbound::Frame(frame)).await;
+    }
+    let _ = direct.send(Outbound::Room(Some(joined.rx))).await;
+}
+
+async fn write_loop<W: AsyncWrite + Unpin + Send>(
+    mut writer: W,
+    mut direct: mpsc::UnboundedReceiver<Queued>,
+    ping_interval: Duration,
+) {
+    let mut room: Option<broadcast::Receiver<Arc<Frame>>> = None;
+    let mut ping = interval_at(Instant::now() + ping_interval, ping_interval);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut token = 0;
+
+    loop {
+        let frames = tokio::select! {
+            // 取出后就归还名额，和有界队列一样
+            out = direct.recv() => match out.map(|(out, _)| out) {
+                Some(Outbound::Frame(frame)) => vec![frame],
+                // 切换前先发完旧房间里已排队的消息，离开或被踢时自己也能收到通知
+                Some(Outbound::Room(rx)) => {
+                    let pending = drain(room.take());
+                    room = rx;
+                    pending
+                }
+                Some(Outbound::Close) | None => break,
+            },
+            frame = recv_room(&mut room) => match frame {
+                Ok(frame) => vec![frame],
+                // 客户端读得太慢，广播队列里的旧消息已被覆盖
+                Err(RecvError::Lagged(n)) => vec![Arc::new(Frame::Error {
+                    message: format!("too slow, {} messages dropped", n),
+                })],
+                // 房间已解散，等待切换指令
+                Err(RecvError::Closed) => {
+                    room = None;
+                    continue;
+                }
+            },
+            _ = ping.tick() => {
+                token += 1;
+                vec![Arc::new(Frame::Ping { token })]
+            }
+        };
+
+        for frame in frames {
+            match timeout(WRITE_TIMEOUT, write_frame(&mut writer, &frame)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) | Err(_) => return,
+            }
+        }
+    }
+}
+
+fn drain(room: Option<broadcast::Receiver<Arc<Frame>>>) -> Vec<Arc<Frame>> {
+    let mut frames = Vec::new();
+    if let Some(mut rx) = room {
+        loop {
+            match rx.try_recv() {
+                Ok(frame) => frames.push(frame),
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+    }
+    frames
+}
+
+// 不在房间时永远等待，只处理私有消息
+async fn recv_room(
+    room: &mut Option<broadcast::Receiver<Arc<Frame>>>,
+) -> Result<Arc<Frame>, RecvError> {
+    match room {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
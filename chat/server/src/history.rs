@@ -0,0 +1,255 @@
+// 房间历史消息：每个房间一个追加写的日志文件，格式与网络帧相同
+//
+// 文件由单独的写入线程读写，聊天时只在内存中追加
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use tokio::sync::oneshot;
+
+use protocol::{Frame, HEADER_SIZE, PROTOCOL_VERSION, ProtocolError, parse_header, write_frame};
+
+// 写入线程中每个房间的日志文件
+struct RoomLog {
+    // 最近 limit 条消息，压缩时重写，也是加载房间时回放的来源
+    messages: VecDeque<Arc<Frame>>,
+    // 写文件失败后为 None，只保留内存中的记录
+    file: Option<File>,
+    // 日志文件中的消息条数，超过 limit 两倍时压缩
+    written: usize,
+}
+
+enum Command {
+    Append(String, Arc<Frame>),
+    // 读出房间最近的消息，排在之前的追加之后，不会漏掉还没写完的消息
+    Load(String, oneshot::Sender<VecDeque<Arc<Frame>>>),
+    Close(String),
+}
+
+/// 内存中只有有人的房间的最近消息，在 ServerState 的锁内使用；
+/// 读写文件都交给写入线程，不在锁内做文件 I/O
+pub struct History {
+    // 每个房间保留的消息条数，0 表示不记录历史
+    limit: usize,
+    rooms: HashMap<String, VecDeque<Arc<Frame>>>,
+    // 不记录历史时为 None
+    writer: Option<mpsc::Sender<Command>>,
+}
+
+impl History {
+    pub fn open(dir: PathBuf, limit: usize) -> io::Result<Self> {
+        let writer = match limit {
+            0 => None,
+            _ => {
+                fs::create_dir_all(&dir)?;
+                let (tx, rx) = mpsc::channel();
+                thread::Builder::new()
+                    .name("history".to_string())
+                    .spawn(move || write_loop(dir, limit, rx))?;
+                Some(tx)
+            }
+        };
+        Ok(History {
+            limit,
+            rooms: HashMap::new(),
+            writer,
+        })
+    }
+
+    pub fn append(&mut self, room: &str, frame: Arc<Frame>) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        // 没加载的房间 (如只有互联服务器用户的房间) 只写文件，加入时再读出来
+        if let Some(messages) = self.rooms.get_mut(room) {
+            messages.push_back(frame.clone());
+            if messages.len() > self.limit {
+                messages.pop_front();
+            }
+        }
+        let _ = writer.send(Command::Append(room.to_string(), frame));
+    }
+
+    /// 房间不在内存中时让写入线程读取，返回等待结果的接收端；
+    /// 调用方在锁外等待，再用 fill 放进内存
+    pub fn load(&self, room: &str) -> Option<oneshot::Receiver<VecDeque<Arc<Frame>>>> {
+        let writer = self.writer.as_ref()?;
+        if self.rooms.contains_key(room) {
+            return None;
+        }
+        let (tx, rx) = oneshot::channel();
+        let _ = writer.send(Command::Load(room.to_string(), tx));
+        Some(rx)
+    }
+
+    /// 放入 load 读出的消息，等待期间别人已经加载过时保留已有的
+    pub fn fill(&mut self, room: &str, messages: VecDeque<Arc<Frame>>) {
+        self.rooms.entry(room.to_string()).or_insert(messages);
+    }
+
+    /// 最近 n 条消息，按时间先后排列
+    pub fn recent(&self, room: &str, n: usize) -> Vec<Arc<Frame>> {
+        let Some(messages) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+        let skip = messages.len().saturating_sub(n);
+        messages.iter().skip(skip).cloned().collect()
+    }
+
+    /// 房间解散时释放内存，文件保留，下次加入时重新加载
+    pub fn close(&mut self, room: &str) {
+        self.rooms.remove(room);
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(Command::Close(room.to_string()));
+        }
+    }
+}
+
+fn write_loop(dir: PathBuf, limit: usize, commands: mpsc::Receiver<Command>) {
+    let mut logs: HashMap<String, RoomLog> = HashMap::new();
+    let path = |room: &str| dir.join(format!("{}.log", file_name(room)));
+
+    for command in commands {
+        match command {
+            Command::Append(room, frame) => {
+                let path = path(&room);
+                let log = logs.entry(room).or_insert_with(|| load(&path, limit));
+                if let Some(file) = &mut log.file {
+                    match write_frame(file, &frame) {
+                        Ok(()) => log.written += 1,
+                        Err(e) => {
+                            println!("history {} write failed: {}", path.display(), e);
+                            log.file = None;
+                        }
+                    }
+                }
+                log.messages.push_back(frame);
+                if log.messages.len() > limit {
+                    log.messages.pop_front();
+                }
+
+                if log.written > limit * 2 {
+                    compact(&path, log);
+                }
+            }
+            Command::Load(room, reply) => {
+                let path = path(&room);
+                let log = logs.entry(room).or_insert_with(|| load(&path, limit));
+                let _ = reply.send(log.messages.clone());
+            }
+            Command::Close(room) => {
+                logs.remove(&room);
+            }
+        }
+    }
+}
+
+fn load(path: &Path, limit: usize) -> RoomLog {
+    let mut messages = VecDeque::new();
+    let mut written = 0;
+    let mut damaged = false;
+
+    match File::open(path) {
+        Ok(file) => {
+            let mut reader = BufReader::new(file);
+            loop {
+                match read_logged(&mut reader) {
+                    Ok(frame) => {
+                        messages.push_back(Arc::new(frame));
+                        if messages.len() > limit {
+                            messages.pop_front();
+                        }
+                        written += 1;
+                    }
+                    Err(ProtocolError::Closed) => break,
+                    // 进程崩溃可能留下半条消息，丢弃之后的内容
+                    Err(e) => {
+                        println!("history {} damaged: {}", path.display(), e);
+                        damaged = true;
+                        break;
+                    }
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => println!("history {} read failed: {}", path.display(), e),
+    }
+
+    let mut log = RoomLog {
+        messages,
+        file: None,
+        written,
+    };
+    if damaged || written > limit {
+        compact(path, &mut log);
+    } else {
+        log.file = open_append(path)
+            .inspect_err(|e| println!("history {} open failed: {}", path.display(), e))
+            .ok();
+    }
+    log
+}
+
+// 只保留内存中的消息重写日志文件，先写临时文件再改名
+fn compact(path: &Path, log: &mut RoomLog) {
+    let tmp = path.with_extension("log.tmp");
+    let result = File::create(&tmp).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        for frame in &log.messages {
+            write_frame(&mut writer, frame).map_err(|e| match e {
+                ProtocolError::Io(e) => e,
+                e => io::Error::new(io::ErrorKind::InvalidData, e),
+            })?;
+        }
+        writer.flush()?;
+        fs::rename(&tmp, path)?;
+        open_append(path)
+    });
+
+    match result {
+        Ok(file) => {
+            log.file = Some(file);
+            log.written = log.messages.len();
+        }
+        Err(e) => {
+            println!("history {} compact failed: {}", path.display(), e);
+            log.file = None;
+        }
+    }
+}
+
+// 日志里只有 Chat 帧，0x01 版本的 Chat 格式与当前相同，旧日志照常读取
+fn read_logged<R: Read>(reader: &mut R) -> Result<Frame, ProtocolError> {
+    let mut header = [0u8; HEADER_SIZE];
+    if reader.read(&mut header[..1])? == 0 {
+        return Err(ProtocolError::Closed);
+    }
+    reader.read_exact(&mut header[1..])?;
+    if header[0] == 0x01 {
+        header[0] = PROTOCOL_VERSION;
+    }
+    let (msg_type, len) = parse_header(&header)?;
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Frame::decode(msg_type, &payload)
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// 房间名可以包含任意非空白字符，文件名只保留字母数字，其余转成 %XX
+fn file_name(room: &str) -> String {
+    let mut name = String::new();
+    for b in room.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+            name.push(b as char);
+        } else {
+            name.push_str(&format!("%{:02X}", b));
+        }
+    }
+    name
+}
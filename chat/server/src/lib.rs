@@ -0,0 +1,105 @@
+// 聊天服务端：server 二进制和 bench 压测工具共用
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+use protocol::Frame;
+use protocol::async_io::write_frame;
+
+use state::ServerState;
+
+// 连接、登录、进出房间等日志，压测时关闭以免淹没结果
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if !$crate::QUIET.load(::std::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub mod accounts;
+pub mod bans;
+mod connection;
+pub mod history;
+pub mod limit;
+pub mod link;
+pub mod state;
+mod transfer;
+mod websocket;
+
+// TLS / WebSocket 握手超时，防止连上不握手的客户端占着任务
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 关闭每个连接的日志，错误仍然打印
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub async fn accept_loop(
+    server: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    state: Arc<ServerState>,
+    clients: Arc<Semaphore>,
+    websocket: bool,
+) {
+    loop {
+        let (socket, addr) = match server.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // 文件描述符耗尽等情况不退出，稍后重试
+                println!("accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        log!("client {} connected", addr);
+
+        // 握手中的连接也占名额
+        let permit = clients.clone().try_acquire_owned().ok();
+        let state = state.clone();
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            match acceptor {
+                Some(acceptor) => match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) if websocket => {
+                        websocket::handle(stream, addr, state, permit).await
+                    }
+                    Ok(Ok(stream)) => serve(stream, addr, state, permit).await,
+                    Ok(Err(e)) => println!("{}: TLS handshake failed: {}", addr, e),
+                    Err(_) => println!("{}: TLS handshake timed out", addr),
+                },
+                None if websocket => websocket::handle(socket, addr, state, permit).await,
+                None => serve(socket, addr, state, permit).await,
+            }
+            log!("closing connection with: {}", addr);
+        });
+    }
+}
+
+// 检查名额和 IP 封禁后交给连接处理，WebSocket 网关也经过这里
+async fn serve<S>(
+    mut stream: S,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    permit: Option<OwnedSemaphorePermit>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let refused = match (&permit, state.check_ip(addr.ip())) {
+        (None, _) => "server is full".to_string(),
+        (Some(_), Err(message)) => message,
+        (Some(_), Ok(())) => return connection::handle(stream, addr, state).await,
+    };
+    println!("{}: refused: {}", addr, refused);
+    let error = Frame::Error { message: refused };
+    let _ = timeout(HANDSHAKE_TIMEOUT, write_frame(&mut stream, &error)).await;
+    let _ = stream.shutdown().await;
+}
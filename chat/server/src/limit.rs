@@ -0,0 +1,83 @@
+// 限流：每个连接一个令牌桶，桶空时丢弃消息；连续超速太多次的自动禁言一段时间
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// 每秒补充的令牌数 (允许的平均消息速率)，0 表示不限流
+    pub rate: f64,
+    /// 桶容量，允许短时间内连发的条数
+    pub burst: u32,
+    /// 连续丢弃这么多条后自动禁言，0 表示不禁言
+    pub strikes: u32,
+    /// 自动禁言的时长
+    pub mute: Duration,
+}
+
+/// 一条消息的处理结果
+pub enum Check {
+    Pass,
+    /// 超速，丢弃；刚开始超速时带上给客户端的提示
+    Drop(Option<String>),
+    /// 刷屏，从现在开始禁言
+    Mute(String),
+    /// 禁言中，不能发言，附剩余时间
+    Muted(Duration),
+}
+
+pub struct Limiter {
+    limits: Limits,
+    tokens: f64,
+    last: Instant,
+    // 连续被丢弃的条数，有消息放行后清零
+    strikes: u32,
+    muted_until: Option<Instant>,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Limiter {
+            limits,
+            tokens: limits.burst as f64,
+            last: Instant::now(),
+            strikes: 0,
+            muted_until: None,
+        }
+    }
+
+    /// speech 为聊天类消息，禁言期间不能发；禁言中的拒绝回复同样要消耗令牌
+    pub fn check(&mut self, speech: bool) -> Check {
+        if self.limits.rate <= 0.0 {
+            return Check::Pass;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limits.rate).min(self.limits.burst as f64);
+        self.last = now;
+        let muted = self.muted_until.filter(|until| *until > now);
+
+        if self.tokens < 1.0 {
+            self.strikes += 1;
+            if self.strikes == self.limits.strikes && muted.is_none() {
+                self.muted_until = Some(now + self.limits.mute);
+                return Check::Mute(format!(
+                    "you have been muted for {}s for flooding",
+                    self.limits.mute.as_secs()
+                ));
+            }
+            if self.strikes == 1 {
+                return Check::Drop(Some(format!(
+                    "you are sending too fast, messages are being dropped (limit {} per second)",
+                    self.limits.rate
+                )));
+            }
+            return Check::Drop(None);
+        }
+        self.tokens -= 1.0;
+        self.strikes = 0;
+        match muted {
+            Some(until) if speech => Check::Muted(until - now),
+            _ => Check::Pass,
+        }
+    }
+}
@@ -0,0 +1,226 @@
+// 服务器互联：服务器之间以 TCP 链接组成一个聊天网络，任何一台上的用户都能看到整个网络的房间和用户。
+//
+// 握手时发起方发送 Link (服务器名和互联口令)，接受方验证口令后以自己的 Link 回复，发起方同样验证。
+// 之后双方先发送各自知道的其他服务器和全部用户，再转发之后的变化 (见 ServerState::link_event)。
+// 网络必须是一棵树：对方或它带来的服务器已经在网络中时拒绝，事件只转发给来源以外的链接，不会绕圈。
+// 链接断开 (netsplit) 时经它到达的用户全部离开，发起方按退避间隔重连，重连后重新同步。
+// 链接不加密，跨公网互联时请走 VPN 或 SSH 隧道
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, MissedTickBehavior, interval_at, sleep, timeout};
+
+use protocol::async_io::{read_frame, write_frame};
+use protocol::{Frame, ProtocolError};
+
+use crate::HANDSHAKE_TIMEOUT;
+use crate::state::{LinkId, ServerState};
+
+// 单帧写超时，对方长时间不读时断开链接
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// 重连间隔从 1 秒开始翻倍，最长 1 分钟
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+/// 接受其他服务器发起的链接
+pub async fn accept_loop(listener: TcpListener, state: Arc<ServerState>, password: String) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("link accept failed: {}", e);
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let state = state.clone();
+        let password = password.clone();
+        tokio::spawn(async move {
+            if let Err(e) = accept(socket, &state, &password).await {
+                println!("link from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// 主动连接 addr 上的服务器，断开或失败后按退避间隔重连
+pub async fn connect_loop(addr: String, state: Arc<ServerState>, password: String) {
+    let mut delay = RECONNECT_MIN;
+    loop {
+        match connect(&addr, &state, &password).await {
+            // 链接建立过，说明对方可用，从最短间隔重新开始
+            Ok(()) => delay = RECONNECT_MIN,
+            Err(e) => println!(
+                "link to {} failed: {}, retrying in {}s",
+                addr,
+                e,
+                delay.as_secs()
+            ),
+        }
+        sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX);
+    }
+}
+
+// 接受方：验证对方的握手，登记链接后回复自己的握手
+async fn accept(mut stream: TcpStream, state: &ServerState, password: &str) -> Result<(), String> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let registered = match read_hello(&mut stream, password).await {
+        Ok(server) => state
+            .add_link(&server, tx.clone())
+            .map(|link| (server, link)),
+        Err(e) => Err(e),
+    };
+    let (server, link) = match registered {
+        Ok(registered) => registered,
+        Err(e) => return Err(refuse(&mut stream, e).await),
+    };
+    // 登记时排进队列的网络现状由写任务发送，排在握手回复之后
+    if let Err(e) = send(&mut stream, &hello(state, password)).await {
+        state.drop_link(link);
+        return Err(e);
+    }
+    run(stream, &server, link, tx, rx, state).await;
+    Ok(())
+}
+
+// 发起方：先发握手，验证对方回复的口令后登记链接；返回 Ok 时链接建立过并已断开
+async fn connect(addr: &str, state: &ServerState, password: &str) -> Result<(), String> {
+    let mut stream = match timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err("connect timed out".to_string()),
+    };
+    send(&mut stream, &hello(state, password)).await?;
+    let server = read_hello(&mut stream, password).await?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let link = match state.add_link(&server, tx.clone()) {
+        Ok(link) => link,
+        Err(e) => return Err(refuse(&mut stream, e).await),
+    };
+    run(stream, &server, link, tx, rx, state).await;
+    Ok(())
+}
+
+fn hello(state: &ServerState, password: &str) -> Frame {
+    Frame::Link {
+        server: state.options.name.clone(),
+        password: password.to_string(),
+    }
+}
+
+// 读对方的握手，返回对方的服务器名
+async fn read_hello(stream: &mut TcpStream, password: &str) -> Result<String, String> {
+    let frame = match timeout(HANDSHAKE_TIMEOUT, read_frame(stream)).await {
+        Ok(Ok(frame)) => frame,
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err("handshake timed out".to_string()),
+    };
+    match frame {
+        Frame::Link {
+            server,
+            password: theirs,
+        } if theirs == password => Ok(server),
+        Frame::Link { .. } => Err("wrong link password".to_string()),
+        Frame::Error { message } => Err(format!("refused: {}", message)),
+        _ => Err("expected a link handshake".to_string()),
+    }
+}
+
+// 告诉对方拒绝的原因，返回原因
+async fn refuse(stream: &mut TcpStream, reason: String) -> String {
+    let error = Frame::Error {
+        message: reason.clone(),
+    };
+    let _ = send(stream, &error).await;
+    reason
+}
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> Result<(), String> {
+    match timeout(WRITE_TIMEOUT, write_frame(writer, frame)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("write timed out".to_string()),
+    }
+}
+
+// 链接建立后的收发，返回时链接已断开，经它到达的用户已离开
+async fn run(
+    stream: TcpStream,
+    server: &str,
+    link: LinkId,
+    tx: mpsc::UnboundedSender<Arc<Frame>>,
+    rx: mpsc::UnboundedReceiver<Arc<Frame>>,
+    state: &ServerState,
+) {
+    println!("linked with {}", server);
+    let (mut reader, writer) = stream.into_split();
+    let mut writer_task = tokio::spawn(write_loop(writer, rx, state.options.ping_interval));
+
+    let read_loop = async {
+        loop {
+            let frame = match timeout(state.options.ping_timeout, read_frame(&mut reader)).await {
+                Ok(Ok(frame)) => frame,
+                Ok(Err(ProtocolError::Closed)) => return "closed by peer".to_string(),
+                Ok(Err(e)) => return e.to_string(),
+                Err(_) => return "ping timeout".to_string(),
+            };
+            match frame {
+                Frame::Ping { token } => {
+                    let _ = tx.send(Arc::new(Frame::Pong { token }));
+                }
+                Frame::Pong { .. } => {}
+                Frame::Error { message } => return format!("error from peer: {}", message),
+                frame => {
+                    if let Err(message) = state.link_event(link, frame) {
+                        let _ = tx.send(Arc::new(Frame::Error {
+                            message: message.clone(),
+                        }));
+                        return message;
+                    }
+                }
+            }
+        }
+    };
+
+    let reason = tokio::select! {
+        reason = read_loop => reason,
+        _ = &mut writer_task => "write failed".to_string(),
+    };
+    state.drop_link(link);
+    println!("link with {} lost: {}", server, reason);
+
+    // 队列关闭后写任务发完剩下的消息 (如给对方的错误说明) 就结束
+    drop(tx);
+    if !writer_task.is_finished() && timeout(WRITE_TIMEOUT, &mut writer_task).await.is_err() {
+        writer_task.abort();
+    }
+}
+
+async fn write_loop<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut rx: mpsc::UnboundedReceiver<Arc<Frame>>,
+    ping_interval: Duration,
+) {
+    let mut ping = interval_at(Instant::now() + ping_interval, ping_interval);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut token = 0;
+
+    loop {
+        let frame = tokio::select! {
+            frame = rx.recv() => match frame {
+                Some(frame) => frame,
+                None => return,
+            },
+            _ = ping.tick() => {
+                token += 1;
+                Arc::new(Frame::Ping { token })
+            }
+        };
+        if send(&mut writer, &frame).await.is_err() {
+            return;
+        }
+    }
+}
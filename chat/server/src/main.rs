@@ -1,60 +1,247 @@
-use std::io::{ErrorKind,Read,Write};
-use std::net::TcpListener;
-use std::sync::mpsc;
-use std::thread;
+use clap::Parser;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
 
+use server::accept_loop;
+use server::accounts::Accounts;
+use server::bans::Bans;
+use server::history::History;
+use server::limit::Limits;
+use server::link;
+use server::state::{Options, ServerState, validate_server};
 
-const LOCAL:&str = "127.0.0.1:6000";
-const MSG_SIZE:u8 = 32;
+mod tls;
 
-fn sleep(){
-    thread::sleep(::std::time::Duration::from_millis(100));
-}
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, args_override_self = true)]
+struct Args {
+    /// 配置文件 (TOML，键名与长参数相同)，命令行参数优先
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// 监听地址，局域网部署时使用 0.0.0.0
+    #[arg(short, long, default_value = "127.0.0.1")]
+    listen: IpAddr,
+
+    /// 监听端口
+    #[arg(short, long, default_value_t = 6000)]
+    port: u16,
+
+    /// WebSocket 监听端口，浏览器客户端以 JSON 消息接入；不指定则不监听
+    #[arg(long)]
+    ws_port: Option<u16>,
+
+    /// 最大同时在线连接数，超出时回复错误后断开
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    max_clients: u32,
+
+    /// 历史消息目录，每个房间一个日志文件
+    #[arg(long, default_value = "chat_history")]
+    history_dir: PathBuf,
+
+    /// 每个房间保留的历史消息条数，0 表示不记录
+    #[arg(long, default_value_t = 1000)]
+    history_limit: usize,
+
+    /// 加入房间时回放的历史消息条数
+    #[arg(long, default_value_t = 20)]
+    replay: usize,
+
+    /// 允许转发的单个文件大小上限 (字节)
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    max_file_size: u64,
+
+    /// 注册账号文件，每行一个昵称和密码哈希
+    #[arg(long, default_value = "chat_accounts")]
+    accounts: PathBuf,
+
+    /// 封禁列表文件，每行一条封禁
+    #[arg(long, default_value = "chat_bans")]
+    bans: PathBuf,
+
+    /// 服务器管理员 (注册账号)，可以在任何房间和全服范围执行管理操作，可重复指定。
+    /// 账号需要先注册好再加入这里，没有账号的管理员昵称谁都不能使用
+    #[arg(long = "admin")]
+    admins: Vec<String>,
+
+    /// 只允许注册账号登录后使用，游客不能聊天
+    #[arg(long)]
+    require_login: bool,
+
+    /// 心跳间隔 (秒)
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval: u64,
+
+    /// 超过这么多秒没收到客户端的任何消息 (包括心跳回复) 就断开，须大于心跳间隔
+    #[arg(long, default_value_t = 90)]
+    ping_timeout: u64,
+
+    /// 每个连接每秒允许的平均消息数，0 表示不限流
+    #[arg(long, default_value_t = 5.0)]
+    rate_limit: f64,
+
+    /// 允许短时间内连发的消息条数
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_burst: u32,
+
+    /// 连续这么多条消息因超速被丢弃后自动禁言，0 表示不自动禁言
+    #[arg(long, default_value_t = 10)]
+    flood_strikes: u32,
 
-fn main() {
-    let server = TcpListener::bind(LOCAL).expect("Listener failed to bind");
-    server.set_nonblocking(true).expect("failed to initialize non_blocking");
+    /// 自动禁言的秒数
+    #[arg(long, default_value_t = 60)]
+    flood_mute: u64,
 
-    let mut clinet = vec![];
+    /// 聊天和私聊消息的长度上限 (字节)
+    #[arg(long, default_value_t = 2000)]
+    max_message_size: usize,
 
-    let (tx,rx) = mpsc::channel::<String>();
-    loop {
-        if let Ok((mut socket,addr)) = server.accept(){
-            println!("client {} connected",addr);
+    /// 服务器名，互联的服务器之间不能重复；默认为监听地址
+    #[arg(long)]
+    name: Option<String>,
 
-            let tx = tx.clone();
-            clinet.push(socket.try_clone().expect("failed to clone client"));
+    /// 服务器互联监听端口，其他服务器连到这里；不指定则不接受互联
+    #[arg(long)]
+    link_port: Option<u16>,
 
+    /// 主动互联的服务器 (host:port，对方的 link_port)，断开后自动重连，可重复指定；
+    /// 两台服务器之间只需一方配置
+    #[arg(long = "link")]
+    links: Vec<String>,
 
-            thread::spawn( move || loop{
-                let mut buff: Vec<u8> = vec![0,MSG_SIZE];
+    /// 服务器互联口令，互联的双方必须相同
+    #[arg(long)]
+    link_password: Option<String>,
 
-                match socket.read_exact(&mut buff) {
-                    Ok(_)=>{
-                        let msg = buff.into_iter().take_while(|&x|x!=0).collect::<Vec<_>>();
-                        let msg = String::from_utf8(msg).expect("Invalid utf8 message!");
+    /// TLS 证书链 (PEM)，与 tls_key 同时指定时启用 TLS
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
 
-                        println!("{}: {}",addr,msg);
-                        tx.send(msg).expect("failed to send msg to rx");
-                    }
-                    Err(ref err) if err.kind() == ErrorKind::WouldBlock=>(),
-                    Err(_)=>{
-                        println!("closing connection with: {}",addr);
-                        break;
-                    }
-                }
-                sleep();
-            });
+    /// TLS 私钥 (PEM)
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = match config_args::args() {
+        Ok(args) => Args::parse_from(args),
+        Err(e) => {
+            eprintln!("failed to load config: {}", e);
+            std::process::exit(2);
         }
+    };
+    if args.ping_timeout <= args.ping_interval {
+        eprintln!("--ping-timeout must be greater than --ping-interval");
+        std::process::exit(2);
+    }
+    let linking = args.link_port.is_some() || !args.links.is_empty();
+    if linking && args.link_password.is_none() {
+        eprintln!("--link-password is required for server links");
+        std::process::exit(2);
+    }
+    let local = SocketAddr::new(args.listen, args.port);
+    let name = args.name.unwrap_or_else(|| local.to_string());
+    if let Err(e) = validate_server(&name) {
+        eprintln!("invalid --name: {}", e);
+        std::process::exit(2);
+    }
 
-        if let Ok(msg) = rx.try_recv(){
-            clinet = clinet.into_iter().filter_map(|mut client|{
-                let mut buff = msg.clone().into_bytes();
-                buff.resize(MSG_SIZE as usize, 0);
-                
+    let history =
+        History::open(args.history_dir, args.history_limit).expect("failed to open history");
 
-                client.write_all(&buff).map(|_| client).ok()
-            }).collect::<Vec<_>>();
+    let accounts = Accounts::load(args.accounts).expect("failed to load accounts");
+    let bans = Bans::load(args.bans).expect("failed to load ban list");
+    for admin in args.admins.iter().filter(|admin| !accounts.exists(admin)) {
+        eprintln!(
+            "warning: admin {} has no account and cannot log in; register it without --admin first",
+            admin
+        );
+    }
+
+    let acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            Some(tls::acceptor(cert, key).expect("failed to load TLS config"))
         }
+        _ => None,
+    };
+
+    let server = TcpListener::bind(local)
+        .await
+        .expect("Listener failed to bind");
+    let scheme = if acceptor.is_some() { "tls" } else { "tcp" };
+    println!("chat server running on {}://{}", scheme, local);
+
+    let ws_server = match args.ws_port {
+        Some(port) => {
+            let local = SocketAddr::new(args.listen, port);
+            let listener = TcpListener::bind(local)
+                .await
+                .expect("WebSocket listener failed to bind");
+            let scheme = if acceptor.is_some() { "wss" } else { "ws" };
+            println!("websocket gateway running on {}://{}", scheme, local);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let link_server = match args.link_port {
+        Some(port) => {
+            let local = SocketAddr::new(args.listen, port);
+            let listener = TcpListener::bind(local)
+                .await
+                .expect("link listener failed to bind");
+            println!("accepting server links on {} as {}", local, name);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let options = Options {
+        name,
+        replay: args.replay,
+        require_login: args.require_login,
+        max_file_size: args.max_file_size,
+        admins: args.admins.into_iter().collect(),
+        ping_interval: Duration::from_secs(args.ping_interval),
+        ping_timeout: Duration::from_secs(args.ping_timeout),
+        limits: Limits {
+            rate: args.rate_limit,
+            burst: args.rate_burst,
+            strikes: args.flood_strikes,
+            mute: Duration::from_secs(args.flood_mute),
+        },
+        max_message_size: args.max_message_size,
+    };
+    let state = Arc::new(ServerState::new(history, accounts, bans, options));
+    let clients = Arc::new(Semaphore::new(args.max_clients as usize));
+
+    let password = args.link_password.unwrap_or_default();
+    if let Some(link_server) = link_server {
+        tokio::spawn(link::accept_loop(
+            link_server,
+            state.clone(),
+            password.clone(),
+        ));
+    }
+    for addr in args.links {
+        tokio::spawn(link::connect_loop(addr, state.clone(), password.clone()));
+    }
+
+    // 两种连接共用状态和连接数上限
+    if let Some(ws_server) = ws_server {
+        let accept = accept_loop(
+            ws_server,
+            acceptor.clone(),
+            state.clone(),
+            clients.clone(),
+            true,
+        );
+        tokio::spawn(accept);
     }
+    accept_loop(server, acceptor, state, clients, false).await
 }
@@ -0,0 +1,1318 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use protocol::{Frame, ModAction, RoomInfo, Status};
+
+use crate::accounts::Accounts;
+use crate::bans::{self, Ban, Bans, SERVER, Target};
+use crate::connection::{Direct, Outbound};
+use crate::history::History;
+use crate::limit::Limits;
+use crate::transfer::{Chunk, Transfers};
+
+// 昵称和房间名长度上限 (字符数)
+pub const MAX_NICK_LEN: usize = 32;
+pub const MAX_ROOM_LEN: usize = 32;
+// 服务器名长度上限，默认的服务器名是监听地址，要放得下 IPv6 地址
+pub const MAX_SERVER_LEN: usize = 64;
+// 登录后自动加入的房间
+pub const DEFAULT_ROOM: &str = "#lobby";
+// 每个房间的广播队列长度，慢客户端落后超过这么多条消息后会丢弃旧消息
+const BROADCAST_CAPACITY: usize = 1024;
+
+pub struct Joined {
+    pub room: String,
+    pub rx: broadcast::Receiver<Arc<Frame>>,
+    pub replay: Vec<Arc<Frame>>,
+}
+
+/// 一次管理操作，即客户端发来的 Frame::Moderate
+pub struct Moderation<'a> {
+    pub action: ModAction,
+    pub room: &'a str,
+    pub target: &'a str,
+    pub duration: u64,
+    pub reason: &'a str,
+}
+
+/// 服务器间链接的编号
+pub type LinkId = u64;
+
+struct User {
+    home: Home,
+    room: Option<String>,
+    status: Status,
+}
+
+// 用户连在哪台服务器上
+enum Home {
+    // 本服务器的连接：发给该用户的私有消息队列和来源 IP
+    Local { direct: Direct, ip: IpAddr },
+    // 其他服务器上的用户，消息经通往该服务器的链接转发
+    Remote(String),
+}
+
+impl User {
+    fn direct(&self) -> Option<&Direct> {
+        match &self.home {
+            Home::Local { direct, .. } => Some(direct),
+            Home::Remote(_) => None,
+        }
+    }
+
+    fn ip(&self) -> Option<IpAddr> {
+        match &self.home {
+            Home::Local { ip, .. } => Some(*ip),
+            Home::Remote(_) => None,
+        }
+    }
+
+    // 其他服务器上的用户所在的服务器
+    fn server(&self) -> Option<&str> {
+        match &self.home {
+            Home::Local { .. } => None,
+            Home::Remote(server) => Some(server),
+        }
+    }
+}
+
+struct Room {
+    tx: broadcast::Sender<Arc<Frame>>,
+    // 房间管理员：创建者自动获得，离开房间后失去
+    ops: HashSet<String>,
+    // 禁言到期时间 (unix 秒，0 为永久)，按昵称记录，离开再进入也不解除
+    muted: HashMap<String, u64>,
+}
+
+struct Registry {
+    users: HashMap<String, User>,
+    rooms: HashMap<String, Room>,
+    history: History,
+    transfers: Transfers,
+    bans: Bans,
+    // 服务器间链接，发往对方的队列
+    links: HashMap<LinkId, mpsc::UnboundedSender<Arc<Frame>>>,
+    // 网络中的其他服务器经哪条链接到达；网络是一棵树，每台服务器只有一条路径
+    servers: HashMap<String, LinkId>,
+    next_link: LinkId,
+}
+
+/// 服务端运行参数，来自命令行或配置文件
+pub struct Options {
+    /// 本服务器在互联网络中的名字，各服务器不能重复
+    pub name: String,
+    /// 加入房间时回放的历史消息条数
+    pub replay: usize,
+    /// 为 true 时游客不能使用任何功能
+    pub require_login: bool,
+    /// 单个文件的大小上限 (字节)
+    pub max_file_size: u64,
+    /// 服务器管理员，须以注册账号登录，在所有房间都有管理权限
+    pub admins: HashSet<String>,
+    /// 向客户端发送心跳的间隔
+    pub ping_interval: Duration,
+    /// 多久没收到任何消息就断开连接
+    pub ping_timeout: Duration,
+    /// 每个连接的限流设置
+    pub limits: Limits,
+    /// 聊天和私聊消息的长度上限 (字节)
+    pub max_message_size: usize,
+}
+
+/// 所有连接共享的服务端状态：在线用户、房间、历史消息和注册账号
+pub struct ServerState {
+    inner: Mutex<Registry>,
+    pub accounts: Accounts,
+    pub options: Options,
+}
+
+impl ServerState {
+    pub fn new(history: History, accounts: Accounts, bans: Bans, options: Options) -> Self {
+        ServerState {
+            inner: Mutex::new(Registry {
+                users: HashMap::new(),
+                rooms: HashMap::new(),
+                history,
+                transfers: Transfers::default(),
+                bans,
+                links: HashMap::new(),
+                servers: HashMap::new(),
+                next_link: 0,
+            }),
+            accounts,
+            options,
+        }
+    }
+
+    /// 连接时检查全服 IP 封禁
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), String> {
+        let now = now();
+        let mut inner = self.inner.lock().unwrap();
+        match inner.bans.find(SERVER, None, ip, now) {
+            Some(ban) => Err(format!("you are banned: {}", ban.describe(now))),
+            None => Ok(()),
+        }
+    }
+
+    // 注册昵称只能通过登录占用，所以检查账号存在即可确认身份
+    fn is_admin(&self, nick: &str) -> bool {
+        self.admin_name(nick) && self.accounts.exists(nick)
+    }
+
+    fn admin_name(&self, nick: &str) -> bool {
+        self.options
+            .admins
+            .iter()
+            .any(|admin| same_nick(admin, nick))
+    }
+
+    /// 管理员昵称的账号需要预先注册 (加入 --admin 之前)，
+    /// 没有账号时游客不能占用或注册，否则谁先注册谁就成了管理员
+    pub fn check_reserved(&self, nick: &str) -> Result<(), String> {
+        match self.admin_name(nick) && !self.accounts.exists(nick) {
+            true => Err(format!("{} is reserved for a server admin", nick)),
+            false => Ok(()),
+        }
+    }
+
+    pub fn is_online(&self, nick: &str) -> bool {
+        self.inner.lock().unwrap().holder(nick).is_some()
+    }
+
+    /// 占用昵称；old 不为空时为改名，保留所在房间并通知房间成员
+    pub fn claim(
+        &self,
+        nick: &str,
+        old: Option<&str>,
+        direct: &Direct,
+        ip: IpAddr,
+    ) -> Result<(), String> {
+        validate_nick(nick)?;
+
+        let now = now();
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(old) = old {
+            inner.owned(old, direct)?;
+        }
+        // 只改大小写的改名不算冲突
+        if let Some(holder) = inner.holder(nick)
+            && Some(holder) != old
+        {
+            return Err(format!("nickname {} is already taken", nick));
+        }
+        if let Some(ban) = inner.bans.find(SERVER, Some(nick), ip, now) {
+            return Err(format!("{} is banned: {}", nick, ban.describe(now)));
+        }
+
+        // 传输以昵称登记，改名后旧的传输全部取消
+        if let Some(old) = old {
+            inner.drop_transfers(old);
+        }
+        let (room, status) = match old.and_then(|old| inner.users.remove(old)) {
+            Some(user) => (user.room, user.status),
+            None => (None, Status::Online),
+        };
+        if let (Some(old), Some(room)) = (old, &room) {
+            // 管理员身份和禁言跟着改名走
+            if let Some(room) = inner.rooms.get_mut(room) {
+                if room.ops.remove(old) {
+                    room.ops.insert(nick.to_string());
+                }
+                if let Some(expires) = take_nick(&mut room.muted, old) {
+                    room.muted.insert(nick.to_string(), expires);
+                }
+            }
+            inner.send(
+                room,
+                Frame::Renamed {
+                    old: old.to_string(),
+                    new: nick.to_string(),
+                },
+            );
+        }
+
+        inner.users.insert(
+            nick.to_string(),
+            User {
+                home: Home::Local {
+                    direct: direct.clone(),
+                    ip,
+                },
+                room,
+                status,
+            },
+        );
+        let event = match old {
+            Some(old) => Frame::Renamed {
+                old: old.to_string(),
+                new: nick.to_string(),
+            },
+            None => Frame::User {
+                nick: nick.to_string(),
+                server: self.options.name.clone(),
+                room: String::new(),
+                status,
+            },
+        };
+        inner.forward(event, None);
+        Ok(())
+    }
+
+    /// 切换到指定房间 (不存在则创建)，返回新房间的订阅和要回放的历史消息
+    pub async fn join(&self, nick: &str, direct: &Direct, room: &str) -> Result<Joined, String> {
+        let room = normalize_room(room)?;
+        let admin = self.is_admin(nick);
+
+        // 房间历史不在内存中时先在锁外等写入线程读出来
+        let loading = self.inner.lock().unwrap().history.load(&room);
+        let loaded = match loading {
+            Some(loading) => Some(loading.await.unwrap_or_default()),
+            None => None,
+        };
+
+        let now = now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.owned(nick, direct)?;
+        if inner.room_of(nick) == Some(room.as_str()) {
+            return Err(format!("already in {}", room));
+        }
+        let ip = inner.users.get(nick).and_then(User::ip);
+        if let Some(ip) = ip.filter(|_| !admin)
+            && let Some(ban) = inner.bans.find(&room, Some(nick), ip, now)
+        {
+            return Err(format!(
+                "you are banned from {}: {}",
+                room,
+                ban.describe(now)
+            ));
+        }
+        inner.leave(nick);
+
+        // 持锁取历史并订阅，回放和实时消息之间不会重复或遗漏
+        if let Some(messages) = loaded {
+            inner.history.fill(&room, messages);
+        }
+        let replay = inner.history.recent(&room, self.options.replay);
+        // 先订阅再广播加入消息，自己也能看到
+        let entry = inner.rooms.entry(room.clone()).or_insert_with(|| Room {
+            tx: broadcast::channel(BROADCAST_CAPACITY).0,
+            // 创建房间的人成为管理员
+            ops: HashSet::from([nick.to_string()]),
+            muted: HashMap::new(),
+        });
+        let rx = entry.tx.subscribe();
+        if let Some(user) = inner.users.get_mut(nick) {
+            user.room = Some(room.clone());
+        }
+        let join = Frame::Join {
+            room: room.clone(),
+            who: nick.to_string(),
+        };
+        inner.send(&room, join.clone());
+        inner.forward(join, None);
+        Ok(Joined { room, rx, replay })
+    }
+
+    /// 离开当前房间
+    pub fn leave(&self, nick: &str, direct: &Direct) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.owned(nick, direct)?;
+        match inner.leave(nick) {
+            Some(room) => {
+                let who = nick.to_string();
+                inner.forward(Frame::Leave { room, who }, None);
+                Ok(())
+            }
+            None => Err("not in a room".to_string()),
+        }
+    }
+
+    /// 向用户所在房间发送聊天消息
+    pub fn say(&self, nick: &str, direct: &Direct, text: String) -> Result<(), String> {
+        let now = now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.owned(nick, direct)?;
+        let room = inner
+            .room_of(nick)
+            .ok_or_else(|| "join a room first".to_string())?
+            .to_string();
+        if let Some(muted) = inner.rooms.get_mut(&room).map(|room| &mut room.muted)
+            && let Some(expires) = take_nick(muted, nick)
+            // 到期的禁言顺带删除
+            && (expires == 0 || expires > now)
+        {
+            muted.insert(nick.to_string(), expires);
+            return Err(format!(
+                "you are muted in {}: {}",
+                room,
+                bans::describe("", expires, now)
+            ));
+        }
+        let frame = Arc::new(Frame::Chat {
+            room: room.clone(),
+            from: nick.to_string(),
+            timestamp: now,
+            text,
+        });
+        inner.history.append(&room, frame.clone());
+        inner.forward(frame.clone(), None);
+        if let Some(room) = inner.rooms.get(&room) {
+            let _ = room.tx.send(frame);
+        }
+        Ok(())
+    }
+
+    /// 设置在线状态并通知所在房间；不在房间时返回给自己的确认
+    pub fn presence(
+        &self,
+        nick: &str,
+        direct: &Direct,
+        status: Status,
+    ) -> Result<Option<Frame>, String> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.owned(nick, direct)?;
+        let user = inner
+            .users
+            .get_mut(nick)
+            .ok_or_else(|| "choose a nickname first".to_string())?;
+        if user.status == status {
+            return Err(format!("you are already {}", status));
+        }
+        user.status = status;
+        let frame = Frame::Presence {
+            who: nick.to_string(),
+            status,
+        };
+        let room = user.room.clone();
+        inner.forward(frame.clone(), None);
+        match room {
+            Some(room) => {
+                inner.send(&room, frame);
+                Ok(None)
+            }
+            None => Ok(Some(frame)),
+        }
+    }
+
+    /// 把正在输入提示转发到所在房间，不在房间时忽略
+    pub fn typing(&self, nick: &str, direct: &Direct, typing: bool) {
+        let inner = self.inner.lock().unwrap();
+        if inner.owned(nick, direct).is_err() {
+            return;
+        }
+        if let Some(room) = inner.room_of(nick) {
+            let frame = Frame::Typing {
+                room: room.to_string(),
+                who: nick.to_string(),
+                typing,
+            };
+            inner.send(room, frame.clone());
+            inner.forward(frame, None);
+        }
+    }
+
+    /// 当前房间最近 n 条历史消息
+    pub fn history(
+        &self,
+        nick: &str,
+        direct: &Direct,
+        n: usize,
+    ) -> Result<Vec<Arc<Frame>>, String> {
+        let inner = self.inner.lock().unwrap();
+        inner.owned(nick, direct)?;
+        let room = inner
+            .room_of(nick)
+            .ok_or_else(|| "join a room first".to_string())?;
+        Ok(inner.history.recent(room, n))
+    }
+
+    /// 私聊，只投递给指定用户，返回回显给发送者的帧
+    pub fn whisper(
+        &self,
+        from: &str,
+        direct: &Direct,
+        to: &str,
+        text: String,
+    ) -> Result<Frame, String> {
+        let inner = self.inner.lock().unwrap();
+        inner.owned(from, direct)?;
+        let to = inner.canonical(to);
+        let frame = Frame::Private {
+            to: to.clone(),
+            from: from.to_string(),
+            timestamp: now(),
+            text,
+        };
+        // 发给自己的只回显一次
+        if from == to {
+            return Ok(frame);
+        }
+        inner.deliver(&to, frame.clone())?;
+        Ok(frame)
+    }
+
+    /// 向 to 提出文件传输
+    pub fn offer_file(
+        &self,
+        from: &str,
+        direct: &Direct,
+        to: &str,
+        offer: Frame,
+    ) -> Result<(), String> {
+        let Frame::FileOffer { id, size, .. } = offer else {
+            return Err("not a file offer".to_string());
+        };
+        if size > self.options.max_file_size {
+            return Err(format!(
+                "file too large: {} > {} bytes",
+                size, self.options.max_file_size
+            ));
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.owned(from, direct)?;
+        let to = inner.canonical(to);
+        let to = to.as_str();
+        if from == to {
+            return Err("cannot send a file to yourself".to_string());
+        }
+        match inner.users.get(to).map(|user| user.server()) {
+            None => return Err(format!("no such user: {}", to)),
+            Some(Some(server)) => {
+                return Err(format!(
+                    "{} is on {}, files cannot be sent across servers",
+                    to, server
+                ));
+            }
+            Some(None) => {}
+        }
+        inner.transfers.offer(from, id, to, size)?;
+        if let Err(e) = inner.deliver(to, offer) {
+            inner.transfers.cancel(from, to, id);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// 接收方回复 from 的传输请求
+    pub fn reply_file(
+        &self,
+        me: &str,
+        direct: &Direct,
+        from: &str,
+        id: u32,
+        accept: bool,
+    ) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.owned(me, direct)?;
+        let from = inner.canonical(from);
+        let from = from.as_str();
+        inner.transfers.reply(from, id, me, accept)?;
+        let reply = Frame::FileReply {
+            id,
+            peer: me.to_string(),
+            accept,
+        };
+        if let Err(e) = inner.deliver(from, reply) {
+            inner.transfers.cancel(me, from, id);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// 登记一块文件数据，返回接收方的消息队列；数据量大，由调用方在锁外等待发送
+    pub fn file_chunk(
+        &self,
+        from: &str,
+        direct: &Direct,
+        id: u32,
+        len: usize,
+    ) -> Result<Option<Direct>, String> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.owned(from, direct)?;
+        match inner.transfers.chunk(from, id, len as u64) {
+            Chunk::Forward(to) => Ok(inner.users.get(&to).and_then(|user| user.direct().cloned())),
+            Chunk::Overflow(to) => {
+                let cancel = Frame::FileCancel {
+                    id,
+                    peer: from.to_string(),
+                    reason: "file larger than announced".to_string(),
+                };
+                let _ = inner.deliver(&to, cancel);
+                Err(format!("file {} is larger than announced", id))
+            }
+            Chunk::Unknown => Ok(None),
+        }
+    }
+
+    /// 取消与 peer 之间的传输并通知对方
+    pub fn cancel_file(
+        &self,
+        me: &str,
+        direct: &Direct,
+        peer: &str,
+        id: u32,
+        reason: String,
+    ) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.owned(me, direct)?;
+        let peer = inner.canonical(peer);
+        let peer = peer.as_str();
+        if !inner.transfers.cancel(me, peer, id) {
+            return Err(format!("no transfer {} with {}", id, peer));
+        }
+        let cancel = Frame::FileCancel {
+            id,
+            peer: me.to_string(),
+            reason,
+        };
+        let _ = inner.deliver(peer, cancel);
+        Ok(())
+    }
+
+    /// 管理操作；房间内的由该房间管理员执行，全服的只有服务器管理员能执行。
+    /// 结果广播到房间，操作者不在房间里时另外回复给操作者
+    pub fn moderate(
+        &self,
+        nick: &str,
+        direct: &Direct,
+        request: Moderation,
+    ) -> Result<Vec<Frame>, String> {
+        let Moderation {
+            action,
+            room,
+            target,
+            duration,
+            reason,
+        } = request;
+        let admin = self.is_admin(nick);
+        // 原因会写进封禁列表，不能带换行
+        let reason = reason.trim().replace(|c: char| c.is_control(), " ");
+        let now = now();
+        let expires = match duration {
+            0 => 0,
+            duration => now.saturating_add(duration),
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.owned(nick, direct)?;
+        let scope = match room {
+            "" => inner
+                .room_of(nick)
+                .ok_or_else(|| "join a room first".to_string())?
+                .to_string(),
+            SERVER if admin => SERVER.to_string(),
+            SERVER => return Err("only server admins can moderate the whole server".to_string()),
+            room => normalize_room(room)?,
+        };
+        let is_op = inner
+            .rooms
+            .get(&scope)
+            .is_some_and(|room| room.ops.contains(nick));
+        if scope != SERVER && !admin && !is_op {
+            return Err(format!("you are not an operator of {}", scope));
+        }
+        let place = match scope.as_str() {
+            SERVER => "the server".to_string(),
+            room => room.to_string(),
+        };
+
+        let target = inner.canonical(target);
+        let target = target.as_str();
+        let punish = matches!(action, ModAction::Kick | ModAction::Ban | ModAction::Mute);
+        if action != ModAction::Bans && target.is_empty() {
+            return Err("missing target".to_string());
+        }
+        if punish && target == nick {
+            return Err("you cannot do that to yourself".to_string());
+        }
+        // 其他服务器上的用户只能由那边的管理员处理
+        if matches!(action, ModAction::Kick | ModAction::Mute | ModAction::Op)
+            && let Some(server) = inner.users.get(target).and_then(User::server)
+        {
+            return Err(format!(
+                "{} is on {}, ask an operator there",
+                target, server
+            ));
+        }
+        if (punish || action == ModAction::Deop) && !admin && self.is_admin(target) {
+            return Err(format!("{} is a server admin", target));
+        }
+        // 房间级的禁言和管理员只对存在的房间有意义
+        let needs_room = !matches!(action, ModAction::Ban | ModAction::Unban | ModAction::Bans);
+        if needs_room && scope == SERVER && action != ModAction::Kick {
+            return Err("this only applies to rooms".to_string());
+        }
+        if needs_room && scope != SERVER && !inner.rooms.contains_key(&scope) {
+            return Err(format!("no such room: {}", scope));
+        }
+
+        let text = match action {
+            ModAction::Kick => {
+                let text = with_reason(
+                    format!("{} was kicked from {} by {}", target, place, nick),
+                    &reason,
+                );
+                if scope == SERVER {
+                    if !inner.users.contains_key(target) {
+                        return Err(format!("no such user: {}", target));
+                    }
+                    inner.disconnect(target, text.clone());
+                    return Ok(vec![notice("", text)]);
+                }
+                if inner.room_of(target) != Some(scope.as_str()) {
+                    return Err(format!("{} is not in {}", target, scope));
+                }
+                // 先广播再踢，被踢的人也能看到原因
+                inner.send(&scope, notice(&scope, text.clone()));
+                inner.kick(&scope, target);
+                return Ok(reply_unless_in(&inner, nick, &scope, text));
+            }
+            ModAction::Ban => {
+                let ban = Ban {
+                    scope: scope.clone(),
+                    target: Target::parse(target)?,
+                    expires,
+                    reason,
+                };
+                let text = format!(
+                    "{} was banned from {} by {} ({})",
+                    target,
+                    place,
+                    nick,
+                    ban.describe(now)
+                );
+                // 在线的匹配用户 (同昵称或同 IP) 立即移出，不波及操作者和服务器管理员
+                let affected: Vec<String> = inner
+                    .users
+                    .iter()
+                    .filter(|(name, user)| {
+                        user.ip()
+                            .is_some_and(|ip| ban.target.matches(Some(name.as_str()), ip))
+                            && name.as_str() != nick
+                            && (scope == SERVER || user.room.as_ref() == Some(&scope))
+                    })
+                    .map(|(name, _)| name.clone())
+                    .filter(|name| !self.is_admin(name))
+                    .collect();
+                inner.bans.add(ban)?;
+                println!("{} banned {} from {}", nick, target, place);
+
+                if scope == SERVER {
+                    for name in &affected {
+                        inner.disconnect(name, text.clone());
+                    }
+                    return Ok(vec![notice("", text)]);
+                }
+                inner.send(&scope, notice(&scope, text.clone()));
+                for name in &affected {
+                    inner.kick(&scope, name);
+                }
+                return Ok(reply_unless_in(&inner, nick, &scope, text));
+            }
+            ModAction::Unban => {
+                inner.bans.remove(&scope, &Target::parse(target)?)?;
+                println!("{} unbanned {} from {}", nick, target, place);
+                format!("{} was unbanned from {} by {}", target, place, nick)
+            }
+            ModAction::Mute => {
+                let room = inner.rooms.get_mut(&scope).unwrap();
+                // 不在线时按输入的写法记录，上线后不区分大小写匹配
+                take_nick(&mut room.muted, target);
+                room.muted.insert(target.to_string(), expires);
+                format!(
+                    "{} was muted in {} by {} ({})",
+                    target,
+                    scope,
+                    nick,
+                    bans::describe(&reason, expires, now)
+                )
+            }
+            ModAction::Unmute => {
+                let room = inner.rooms.get_mut(&scope).unwrap();
+                if take_nick(&mut room.muted, target).is_none() {
+                    return Err(format!("{} is not muted in {}", target, scope));
+                }
+                format!("{} was unmuted in {} by {}", target, scope, nick)
+            }
+            ModAction::Op => {
+                if inner.room_of(target) != Some(scope.as_str()) {
+                    return Err(format!("{} is not in {}", target, scope));
+                }
+                let room = inner.rooms.get_mut(&scope).unwrap();
+                if !room.ops.insert(target.to_string()) {
+                    return Err(format!("{} is already an operator of {}", target, scope));
+                }
+                format!("{} made {} an operator of {}", nick, target, scope)
+            }
+            ModAction::Deop => {
+                let room = inner.rooms.get_mut(&scope).unwrap();
+                if !room.ops.remove(target) {
+                    return Err(format!("{} is not an operator of {}", target, scope));
+                }
+                format!("{} removed {} as operator of {}", nick, target, scope)
+            }
+            ModAction::Bans => {
+                let list = inner.bans.list(&scope, now);
+                if list.is_empty() {
+                    return Ok(vec![notice("", format!("no bans in {}", place))]);
+                }
+                return Ok(list
+                    .iter()
+                    .map(|ban| notice("", format!("{}: {}", ban.target, ban.describe(now))))
+                    .collect());
+            }
+        };
+
+        if scope == SERVER {
+            return Ok(vec![notice("", text)]);
+        }
+        inner.send(&scope, notice(&scope, text.clone()));
+        Ok(reply_unless_in(&inner, nick, &scope, text))
+    }
+
+    /// 所有房间及成员，按名字排序
+    pub fn list(&self) -> Vec<RoomInfo> {
+        let inner = self.inner.lock().unwrap();
+        let mut rooms: Vec<RoomInfo> = inner
+            .rooms
+            .iter()
+            .map(|(name, room)| {
+                let mut members: Vec<(&String, &User)> = inner
+                    .users
+                    .iter()
+                    .filter(|(_, user)| user.room.as_ref() == Some(name))
+                    .collect();
+                members.sort_by_key(|(nick, _)| *nick);
+                let mut ops: Vec<String> = room.ops.iter().cloned().collect();
+                ops.sort();
+                RoomInfo {
+                    name: name.clone(),
+                    away: members
+                        .iter()
+                        .filter(|(_, user)| user.status == Status::Away)
+                        .map(|(nick, _)| nick.to_string())
+                        .collect(),
+                    members: members.iter().map(|(nick, _)| nick.to_string()).collect(),
+                    ops,
+                }
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+
+    /// 连接断开：离开房间并释放昵称。昵称冲突时已被其他服务器的用户接管的不再处理
+    pub fn release(&self, nick: &str, direct: &Direct) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.owned(nick, direct).is_err() {
+            return;
+        }
+        inner.leave(nick);
+        inner.drop_transfers(nick);
+        inner.users.remove(nick);
+        inner.forward(
+            Frame::Quit {
+                nick: nick.to_string(),
+            },
+            None,
+        );
+    }
+
+    /// 登记与 server 的新链接，先把网络现状排进发往对方的队列：其他服务器在前，用户在后
+    pub fn add_link(
+        &self,
+        server: &str,
+        tx: mpsc::UnboundedSender<Arc<Frame>>,
+    ) -> Result<LinkId, String> {
+        validate_server(server)?;
+        let mut inner = self.inner.lock().unwrap();
+        if server == self.options.name || inner.servers.contains_key(server) {
+            return Err(format!("{} is already on the network", server));
+        }
+
+        for name in inner.servers.keys() {
+            let _ = tx.send(Arc::new(Frame::Server { name: name.clone() }));
+        }
+        for (nick, user) in &inner.users {
+            let user = Frame::User {
+                nick: nick.clone(),
+                server: user.server().unwrap_or(&self.options.name).to_string(),
+                room: user.room.clone().unwrap_or_default(),
+                status: user.status,
+            };
+            let _ = tx.send(Arc::new(user));
+        }
+
+        // 先通知其余链接，再登记新链接
+        let name = server.to_string();
+        inner.forward(Frame::Server { name: name.clone() }, None);
+        inner.next_link += 1;
+        let link = inner.next_link;
+        inner.links.insert(link, tx);
+        inner.servers.insert(name, link);
+        Ok(link)
+    }
+
+    /// 链接断开：经它到达的服务器全部离开网络，并通知其余链接
+    pub fn drop_link(&self, link: LinkId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.links.remove(&link);
+        let lost: Vec<String> = inner
+            .servers
+            .iter()
+            .filter(|(_, via)| **via == link)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for server in lost {
+            inner.split(&server);
+            inner.forward(Frame::Split { server }, None);
+        }
+    }
+
+    /// 处理从链接收到的事件，本地生效后转发给其余链接。
+    /// Err 表示对方违反协议 (如链接成环)，应断开链接；过时的事件 (用户已经不在了) 直接忽略
+    pub fn link_event(&self, link: LinkId, frame: Frame) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+        match frame {
+            Frame::Server { name } => {
+                validate_server(&name)?;
+                if name == self.options.name || inner.servers.contains_key(&name) {
+                    return Err(format!(
+                        "{} is already on the network, links would form a loop",
+                        name
+                    ));
+                }
+                inner.servers.insert(name.clone(), link);
+                inner.forward(Frame::Server { name }, Some(link));
+            }
+            Frame::Split { server } => {
+                if inner.servers.get(&server) == Some(&link) {
+                    inner.split(&server);
+                    inner.forward(Frame::Split { server }, Some(link));
+                }
+            }
+            Frame::User {
+                nick,
+                server,
+                room,
+                status,
+            } => {
+                if inner.servers.get(&server) != Some(&link) {
+                    return Err(format!("{} is on unknown server {}", nick, server));
+                }
+                validate_nick(&nick)?;
+                if !inner.collide(&nick, &server, &self.options.name) {
+                    return Ok(());
+                }
+                let home = Home::Remote(server.clone());
+                let user = User {
+                    home,
+                    room: None,
+                    status,
+                };
+                inner.users.insert(nick.clone(), user);
+                if !room.is_empty() {
+                    inner.enter(&nick, &normalize_room(&room)?);
+                }
+                let user = Frame::User {
+                    nick,
+                    server,
+                    room,
+                    status,
+                };
+                inner.forward(user, Some(link));
+            }
+            Frame::Quit { nick } => {
+                if inner.remote_via(&nick, link) {
+                    inner.leave(&nick);
+                    inner.users.remove(&nick);
+                    inner.forward(Frame::Quit { nick }, Some(link));
+                }
+            }
+            Frame::Renamed { old, new } => {
+                if !inner.remote_via(&old, link) {
+                    return Ok(());
+                }
+                validate_nick(&new)?;
+                let server = inner.users[&old].server().unwrap_or_default().to_string();
+                // 新昵称撞上了名字靠前的服务器上的用户，改名的一方按退出处理
+                if !same_nick(&old, &new) && !inner.collide(&new, &server, &self.options.name) {
+                    inner.leave(&old);
+                    inner.users.remove(&old);
+                    inner.forward(Frame::Quit { nick: old }, Some(link));
+                    return Ok(());
+                }
+                let user = inner.users.remove(&old).unwrap();
+                let renamed = Frame::Renamed {
+                    old,
+                    new: new.clone(),
+                };
+                if let Some(room) = &user.room {
+                    inner.send(room, renamed.clone());
+                }
+                inner.users.insert(new, user);
+                inner.forward(renamed, Some(link));
+            }
+            Frame::Join { room, who } => {
+                if inner.remote_via(&who, link) {
+                    let room = normalize_room(&room)?;
+                    if inner.room_of(&who) != Some(room.as_str()) {
+                        inner.enter(&who, &room);
+                        inner.forward(Frame::Join { room, who }, Some(link));
+                    }
+                }
+            }
+            Frame::Leave { who, .. } => {
+                if inner.remote_via(&who, link)
+                    && let Some(room) = inner.leave(&who)
+                {
+                    inner.forward(Frame::Leave { room, who }, Some(link));
+                }
+            }
+            Frame::Chat {
+                ref room, ref from, ..
+            } => {
+                if inner.remote_via(from, link) && inner.room_of(from) == Some(room.as_str()) {
+                    let room = room.clone();
+                    let frame = Arc::new(frame);
+                    inner.history.append(&room, frame.clone());
+                    inner.forward(frame.clone(), Some(link));
+                    if let Some(room) = inner.rooms.get(&room) {
+                        let _ = room.tx.send(frame);
+                    }
+                }
+            }
+            // 私聊只沿通往收件人的路径转发
+            Frame::Private {
+                ref to, ref from, ..
+            } => {
+                if inner.remote_via(from, link) {
+                    let to = to.clone();
+                    let _ = inner.deliver(&to, frame);
+                }
+            }
+            Frame::Presence { who, status } => {
+                if inner.remote_via(&who, link) {
+                    let user = inner.users.get_mut(&who).unwrap();
+                    user.status = status;
+                    let room = user.room.clone();
+                    let frame = Frame::Presence { who, status };
+                    if let Some(room) = room {
+                        inner.send(&room, frame.clone());
+                    }
+                    inner.forward(frame, Some(link));
+                }
+            }
+            Frame::Typing {
+                ref room, ref who, ..
+            } => {
+                if inner.remote_via(who, link) && inner.room_of(who) == Some(room.as_str()) {
+                    inner.send(room, frame.clone());
+                    inner.forward(frame, Some(link));
+                }
+            }
+            frame => {
+                return Err(format!(
+                    "unexpected frame type 0x{:02x} on server link",
+                    frame.msg_type()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Registry {
+    // 只有连接本人能以自己的昵称操作：昵称冲突落败或已断开的连接不能再冒用，
+    // 那时这个昵称可能已属于其他服务器上的用户
+    fn owned(&self, nick: &str, direct: &Direct) -> Result<(), String> {
+        match self.users.get(nick).and_then(User::direct) {
+            Some(own) if own.same_channel(direct) => Ok(()),
+            _ => Err(format!("you are no longer logged in as {}", nick)),
+        }
+    }
+
+    // 占用着 nick (不区分大小写) 的用户的昵称
+    fn holder(&self, nick: &str) -> Option<&str> {
+        if let Some((holder, _)) = self.users.get_key_value(nick) {
+            return Some(holder);
+        }
+        self.users
+            .keys()
+            .find(|holder| same_nick(holder, nick))
+            .map(String::as_str)
+    }
+
+    // 客户端输入的昵称换成在线用户实际的写法，不在线的原样返回。
+    // 用户表、管理员和禁言都以实际写法为键，查找前都要先换
+    fn canonical(&self, nick: &str) -> String {
+        self.holder(nick).unwrap_or(nick).to_string()
+    }
+
+    fn room_of(&self, nick: &str) -> Option<&str> {
+        self.users.get(nick)?.room.as_deref()
+    }
+
+    fn send(&self, room: &str, frame: Frame) {
+        if let Some(room) = self.rooms.get(room) {
+            let _ = room.tx.send(Arc::new(frame));
+        }
+    }
+
+    // 转发给其他服务器，except 为事件来的那条链接；网络是一棵树，这样事件不会绕圈
+    fn forward(&self, frame: impl Into<Arc<Frame>>, except: Option<LinkId>) {
+        if self.links.is_empty() {
+            return;
+        }
+        let frame = frame.into();
+        for (id, tx) in &self.links {
+            if Some(*id) != except {
+                let _ = tx.send(frame.clone());
+            }
+        }
+    }
+
+    // nick 是否为经 link 到达的其他服务器上的用户；链接只能代表它那一侧的用户
+    fn remote_via(&self, nick: &str, link: LinkId) -> bool {
+        self.users
+            .get(nick)
+            .and_then(User::server)
+            .is_some_and(|server| self.servers.get(server) == Some(&link))
+    }
+
+    // server 上的用户要使用 nick 时检查冲突 (通常是断开期间两边各自有人用了同一个昵称)：
+    // 名字靠前的服务器上的用户保留，每台服务器按同样的规则处理，结果一致。
+    // 返回 true 表示 nick 可以给 server 上的用户，输掉的原用户已被移除
+    fn collide(&mut self, nick: &str, server: &str, me: &str) -> bool {
+        let Some(nick) = self.holder(nick).map(str::to_string) else {
+            return true;
+        };
+        let nick = nick.as_str();
+        let user = &self.users[nick];
+        if user.server().unwrap_or(me) <= server {
+            return false;
+        }
+        if user.direct().is_some() {
+            println!("{} lost a nickname collision with {}", nick, server);
+            let text = format!("nickname collision: {} is also in use on {}", nick, server);
+            self.disconnect(nick, text);
+            self.drop_transfers(nick);
+        }
+        self.leave(nick);
+        self.users.remove(nick);
+        true
+    }
+
+    // 其他服务器上的用户进入房间，房间不存在则创建 (没有管理员)
+    fn enter(&mut self, nick: &str, room: &str) {
+        self.leave(nick);
+        self.rooms.entry(room.to_string()).or_insert_with(|| Room {
+            tx: broadcast::channel(BROADCAST_CAPACITY).0,
+            ops: HashSet::new(),
+            muted: HashMap::new(),
+        });
+        if let Some(user) = self.users.get_mut(nick) {
+            user.room = Some(room.to_string());
+        }
+        self.send(
+            room,
+            Frame::Join {
+                room: room.to_string(),
+                who: nick.to_string(),
+            },
+        );
+    }
+
+    // 服务器离开网络 (断线)：先提示受影响的房间，再让它上面的用户逐个离开
+    fn split(&mut self, server: &str) {
+        self.servers.remove(server);
+        let gone: Vec<String> = self
+            .users
+            .iter()
+            .filter(|(_, user)| user.server() == Some(server))
+            .map(|(nick, _)| nick.clone())
+            .collect();
+        let mut rooms: Vec<String> = gone
+            .iter()
+            .filter_map(|nick| self.room_of(nick).map(str::to_string))
+            .collect();
+        rooms.sort();
+        rooms.dedup();
+        for room in &rooms {
+            let text = format!("netsplit: {} left the network", server);
+            self.send(room, notice(room, text));
+        }
+        for nick in &gone {
+            self.leave(nick);
+            self.users.remove(nick);
+        }
+    }
+
+    // 踢出房间：先广播离开，再让写任务退订；写任务会先发完旧房间里已排队的消息
+    fn kick(&mut self, room: &str, nick: &str) {
+        if self.room_of(nick) != Some(room) {
+            return;
+        }
+        self.leave(nick);
+        let leave = Frame::Leave {
+            room: room.to_string(),
+            who: nick.to_string(),
+        };
+        self.forward(leave, None);
+        if let Some(direct) = self.users.get(nick).and_then(User::direct) {
+            direct.control(Outbound::Room(None));
+        }
+    }
+
+    // 断开连接，昵称在连接结束时释放
+    fn disconnect(&mut self, nick: &str, text: String) {
+        if let Some(direct) = self.users.get(nick).and_then(User::direct) {
+            let notice = Frame::Notice {
+                room: String::new(),
+                text,
+            };
+            direct.control(Outbound::Frame(Arc::new(notice)));
+            direct.control(Outbound::Close);
+        }
+    }
+
+    // 投递给单个用户；持锁时不能等待，对方队列满了直接报错。
+    // 其他服务器上的用户经链接转发，由那边投递
+    fn deliver(&self, to: &str, frame: Frame) -> Result<(), String> {
+        let user = self
+            .holder(to)
+            .and_then(|holder| self.users.get(holder))
+            .ok_or_else(|| format!("no such user: {}", to))?;
+        let direct = match &user.home {
+            Home::Local { direct, .. } => direct,
+            Home::Remote(server) => {
+                let link = self.servers.get(server).and_then(|id| self.links.get(id));
+                return match link.map(|tx| tx.send(Arc::new(frame))) {
+                    Some(Ok(())) => Ok(()),
+                    _ => Err(format!("{} is offline", to)),
+                };
+            }
+        };
+        match direct.try_send(Outbound::Frame(Arc::new(frame))) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(format!("{} is not reading messages", to)),
+            Err(TrySendError::Closed(_)) => Err(format!("{} is offline", to)),
+        }
+    }
+
+    fn drop_transfers(&mut self, nick: &str) {
+        for (peer, id) in self.transfers.drop_user(nick) {
+            let cancel = Frame::FileCancel {
+                id,
+                peer: nick.to_string(),
+                reason: format!("{} went away", nick),
+            };
+            let _ = self.deliver(&peer, cancel);
+        }
+    }
+
+    // 离开当前房间并通知其余成员，房间空了就删除，返回离开的房间
+    fn leave(&mut self, nick: &str) -> Option<String> {
+        let room = self.users.get_mut(nick)?.room.take()?;
+        if let Some(entry) = self.rooms.get_mut(&room) {
+            entry.ops.remove(nick);
+        }
+        self.send(
+            &room,
+            Frame::Leave {
+                room: room.clone(),
+                who: nick.to_string(),
+            },
+        );
+        if !self
+            .users
+            .values()
+            .any(|user| user.room.as_ref() == Some(&room))
+        {
+            self.rooms.remove(&room);
+            self.history.close(&room);
+        }
+        Some(room)
+    }
+}
+
+fn notice(room: &str, text: String) -> Frame {
+    Frame::Notice {
+        room: room.to_string(),
+        text,
+    }
+}
+
+// 操作者在房间里时已收到广播，不用重复回复
+fn reply_unless_in(inner: &Registry, nick: &str, room: &str, text: String) -> Vec<Frame> {
+    match inner.room_of(nick) == Some(room) {
+        true => Vec::new(),
+        false => vec![notice(room, text)],
+    }
+}
+
+fn with_reason(text: String, reason: &str) -> String {
+    match reason {
+        "" => text,
+        reason => format!("{} ({})", text, reason),
+    }
+}
+
+// 房间名统一以 # 开头，客户端可以省略
+fn normalize_room(room: &str) -> Result<String, String> {
+    let room = room.trim();
+    let room = if room.starts_with('#') {
+        room.to_string()
+    } else {
+        format!("#{}", room)
+    };
+    validate_name(&room[1..], MAX_ROOM_LEN, "room name")?;
+    Ok(room)
+}
+
+// 取出以 nick (不区分大小写) 为键的记录
+fn take_nick<V>(map: &mut HashMap<String, V>, nick: &str) -> Option<V> {
+    if let Some(value) = map.remove(nick) {
+        return Some(value);
+    }
+    let key = map.keys().find(|key| same_nick(key, nick))?.clone();
+    map.remove(&key)
+}
+
+/// 昵称不区分大小写，Alice 和 alice 是同一个人
+pub fn same_nick(a: &str, b: &str) -> bool {
+    a == b || a.to_lowercase() == b.to_lowercase()
+}
+
+pub fn validate_nick(nick: &str) -> Result<(), String> {
+    validate_name(nick, MAX_NICK_LEN, "nickname")
+}
+
+pub fn validate_server(name: &str) -> Result<(), String> {
+    validate_name(name, MAX_SERVER_LEN, "server name")
+}
+
+fn validate_name(name: &str, max_len: usize, what: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err(format!("{} must not be empty", what));
+    }
+    if name.chars().count() > max_len {
+        return Err(format!("{} longer than {} characters", what, max_len));
+    }
+    if name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(format!("{} must not contain whitespace", what));
+    }
+    Ok(())
+}
+
+// 消息时间戳，unix 秒
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
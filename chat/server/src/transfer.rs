@@ -0,0 +1,112 @@
+// 文件传输登记：只转发已被接收方接受、且不超过声明大小的数据
+use std::collections::HashMap;
+
+/// 一块数据的去向
+pub enum Chunk {
+    /// 转发给接收方
+    Forward(String),
+    /// 超出声明的大小，传输已取消，需要通知接收方
+    Overflow(String),
+    /// 未登记或尚未接受；取消后仍在路上的数据块会落到这里，直接丢弃
+    Unknown,
+}
+
+struct Transfer {
+    to: String,
+    size: u64,
+    sent: u64,
+    accepted: bool,
+}
+
+/// 以 (发送方, 发送方分配的 id) 为键
+#[derive(Default)]
+pub struct Transfers {
+    active: HashMap<(String, u32), Transfer>,
+}
+
+impl Transfers {
+    pub fn offer(&mut self, from: &str, id: u32, to: &str, size: u64) -> Result<(), String> {
+        let key = (from.to_string(), id);
+        if self.active.contains_key(&key) {
+            return Err(format!("transfer {} already in progress", id));
+        }
+        self.active.insert(
+            key,
+            Transfer {
+                to: to.to_string(),
+                size,
+                sent: 0,
+                accepted: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// 接收方回复，拒绝时删除登记
+    pub fn reply(&mut self, from: &str, id: u32, me: &str, accept: bool) -> Result<(), String> {
+        let key = (from.to_string(), id);
+        match self.active.get_mut(&key) {
+            Some(transfer) if transfer.to == me && !transfer.accepted => {
+                // 空文件不会有数据块，接受即完成
+                if accept && transfer.size > 0 {
+                    transfer.accepted = true;
+                } else {
+                    self.active.remove(&key);
+                }
+                Ok(())
+            }
+            _ => Err(format!("no pending file {} from {}", id, from)),
+        }
+    }
+
+    /// 登记一块数据；收满或超出大小后删除登记
+    pub fn chunk(&mut self, from: &str, id: u32, len: u64) -> Chunk {
+        let key = (from.to_string(), id);
+        let transfer = match self.active.get_mut(&key) {
+            Some(transfer) if transfer.accepted => transfer,
+            _ => return Chunk::Unknown,
+        };
+        if transfer.sent + len > transfer.size {
+            let transfer = self.active.remove(&key).unwrap();
+            return Chunk::Overflow(transfer.to);
+        }
+        transfer.sent += len;
+        let to = transfer.to.clone();
+        if transfer.sent == transfer.size {
+            self.active.remove(&key);
+        }
+        Chunk::Forward(to)
+    }
+
+    /// 任意一方取消，me 可以是发送方也可以是接收方
+    pub fn cancel(&mut self, me: &str, peer: &str, id: u32) -> bool {
+        let outgoing = (me.to_string(), id);
+        if self.active.get(&outgoing).is_some_and(|t| t.to == peer) {
+            self.active.remove(&outgoing);
+            return true;
+        }
+        let incoming = (peer.to_string(), id);
+        if self.active.get(&incoming).is_some_and(|t| t.to == me) {
+            self.active.remove(&incoming);
+            return true;
+        }
+        false
+    }
+
+    /// 用户下线或改名，取消相关的所有传输，返回需要通知的 (对方, id)
+    pub fn drop_user(&mut self, nick: &str) -> Vec<(String, u32)> {
+        let mut notify = Vec::new();
+        self.active.retain(|(from, id), transfer| {
+            if from == nick {
+                notify.push((transfer.to.clone(), *id));
+                false
+            } else if transfer.to == nick {
+                notify.push((from.clone(), *id));
+                false
+            } else {
+                true
+            }
+        });
+        notify
+    }
+}
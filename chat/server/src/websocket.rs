@@ -0,0 +1,123 @@
+// WebSocket 网关：浏览器客户端用 JSON 文本消息收发帧
+//
+// 每个 WebSocket 连接在内存中转换成二进制帧流，交给与 TCP 客户端相同的连接处理，
+// 房间、昵称和私聊因此在两种连接之间互通。心跳同样是 JSON 消息：
+// 收到 {"type": "ping", "token": n} 要回复 {"type": "pong", "token": n}，否则会超时断开
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures_util::{Sink, SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, mpsc};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+
+use protocol::async_io::read_frame;
+use protocol::{Frame, MAX_PAYLOAD_SIZE};
+
+use crate::state::ServerState;
+use crate::{HANDSHAKE_TIMEOUT, serve};
+
+// 内存管道的缓冲区，够放下几个最大的帧
+const PIPE_CAPACITY: usize = 4 * MAX_PAYLOAD_SIZE;
+const OUTGOING_CAPACITY: usize = 64;
+
+pub async fn handle<S>(
+    stream: S,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    permit: Option<OwnedSemaphorePermit>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let ws = match timeout(HANDSHAKE_TIMEOUT, tokio_tungstenite::accept_async(stream)).await {
+        Ok(Ok(ws)) => ws,
+        Ok(Err(e)) => return println!("{}: WebSocket handshake failed: {}", addr, e),
+        Err(_) => return println!("{}: WebSocket handshake timed out", addr),
+    };
+    let (mut sink, mut source) = ws.split();
+
+    let (gateway, server) = tokio::io::duplex(PIPE_CAPACITY);
+    let (mut reader, mut writer) = tokio::io::split(gateway);
+    let session = tokio::spawn(serve(server, addr, state, permit));
+
+    // 服务端发来的帧转成 JSON；read_frame 不能在 select! 里取消，放在单独任务
+    let (tx, mut outgoing) = mpsc::channel(OUTGOING_CAPACITY);
+    tokio::spawn(async move {
+        while let Ok(frame) = read_frame(&mut reader).await {
+            if tx.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        let frame = tokio::select! {
+            message = source.next() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<Frame>(&text) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        let error = Frame::Error {
+                            message: format!("bad JSON message: {}", e),
+                        };
+                        if send(&mut sink, &error).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                },
+                Some(Ok(Message::Binary(_))) => {
+                    let error = Frame::Error {
+                        message: "send frames as JSON text messages".to_string(),
+                    };
+                    if send(&mut sink, &error).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                // ping 由 tungstenite 自动回复
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                _ => break,
+            },
+            // 连接处理结束 (如服务端已满)，关闭 WebSocket
+            frame = outgoing.recv() => match frame {
+                Some(frame) => {
+                    if send(&mut sink, &frame).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                None => break,
+            },
+        };
+        // 编码失败 (如超过帧长度上限) 的消息只回复错误，写进管道会断开整个连接
+        let bytes = match frame.encode() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                let error = Frame::Error {
+                    message: format!("message rejected: {}", e),
+                };
+                if send(&mut sink, &error).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        if writer.write_all(&bytes).await.is_err() {
+            break;
+        }
+    }
+
+    let _ = sink.close().await;
+    // 关闭管道，连接处理会当作客户端断开
+    let _ = writer.shutdown().await;
+    let _ = session.await;
+}
+
+async fn send<S>(sink: &mut S, frame: &Frame) -> Result<(), S::Error>
+where
+    S: Sink<Message> + Unpin,
+{
+    let json = serde_json::to_string(frame).expect("frame serializes to JSON");
+    sink.send(Message::text(json)).await
+}
@@ -0,0 +1,59 @@
+// 配置文件：TOML，键名与命令行长参数一致 (如 history-limit = 500)
+//
+// 文件中的配置展开成命令行参数放在真实参数之前，命令行同名参数会覆盖文件中的值，
+// 所以 clap 需要开启 args_override_self；可重复的参数写成数组
+use std::ffi::OsString;
+use std::fs;
+
+use toml::{Table, Value};
+
+/// 返回合并了配置文件 (--config 指定) 的命令行参数
+pub fn args() -> Result<Vec<OsString>, String> {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+
+    let content = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let table: Table = content.parse().map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut expanded = Vec::new();
+    for (key, value) in table {
+        let flag = format!("--{}", key.replace('_', "-"));
+        if flag == "--config" {
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Boolean(true) => expanded.push(flag.clone()),
+                Value::Boolean(false) => {}
+                Value::String(s) => expanded.extend([flag.clone(), s]),
+                Value::Integer(_) | Value::Float(_) => {
+                    expanded.extend([flag.clone(), value.to_string()])
+                }
+                _ => return Err(format!("{}: unsupported value for {}", path, key)),
+            }
+        }
+    }
+
+    args.splice(1..1, expanded.into_iter().map(OsString::from));
+    Ok(args)
+}
+
+// 在解析前先找出 --config PATH 或 --config=PATH
+fn config_path(args: &[OsString]) -> Option<String> {
+    let mut iter = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = iter.next() {
+        if arg == "--config" {
+            return iter.next().map(|path| path.into_owned());
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
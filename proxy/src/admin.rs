@@ -0,0 +1,163 @@
+// src/admin.rs
+//
+// 本地管理接口，简单的 HTTP/JSON：
+//   GET    /health                 运行状态
+//   GET    /config                 当前配置 (不含密码)
+//   GET    /sessions               活跃会话列表
+//   GET    /blocklists             黑名单条目数和拦截次数
+//   DELETE /sessions/{id}          终止单个会话
+//   DELETE /users/{name}/sessions  终止某个用户的全部会话
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, warn};
+
+use crate::auth::UserConfig;
+
+// 请求头最大长度，管理接口不接受请求体
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+pub struct AdminState {
+    pub config: Arc<UserConfig>,
+    // 启动参数快照
+    pub settings: Value,
+    pub started: Instant,
+}
+
+#[derive(Serialize)]
+struct SessionInfo<'a> {
+    id: u64,
+    user: Option<&'a str>,
+    target: &'a str,
+    upstream: Option<String>,
+    started: u64,
+    up: u64,
+    down: u64,
+}
+
+pub async fn serve(listener: TcpListener, state: Arc<AdminState>) -> Result<(), Box<dyn Error>> {
+    info!("Admin API running on {}", listener.local_addr()?);
+
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(socket, &state).await {
+                error!("[Admin] from {:?} : {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut socket: TcpStream, state: &AdminState) -> Result<(), Box<dyn Error>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err("连接提前关闭".into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_SIZE {
+            respond(&mut socket, 413, &json!({ "error": "request too large" })).await?;
+            return Ok(());
+        }
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    let (status, body) = route(method, path, state);
+    respond(&mut socket, status, &body).await?;
+    Ok(())
+}
+
+fn route(method: &str, path: &str, state: &AdminState) -> (u16, Value) {
+    let sessions = &state.config.sessions;
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (method, segments.as_slice()) {
+        ("GET", ["health"]) => (
+            200,
+            json!({
+                "status": "ok",
+                "uptime": state.started.elapsed().as_secs(),
+                "sessions": sessions.len(),
+            }),
+        ),
+        ("GET", ["config"]) => (200, state.settings.clone()),
+        ("GET", ["sessions"]) => {
+            let list = sessions.list();
+            let infos: Vec<SessionInfo> = list
+                .iter()
+                .map(|s| SessionInfo {
+                    id: s.id,
+                    user: s.user.as_deref(),
+                    target: &s.target,
+                    upstream: s.upstream.as_ref().map(|u| u.name()),
+                    started: s.started_secs(),
+                    up: s.up.load(Ordering::Relaxed),
+                    down: s.down.load(Ordering::Relaxed),
+                })
+                .collect();
+            (200, json!(infos))
+        }
+        ("GET", ["blocklists"]) => {
+            let lists = state.config.blocklists.as_ref().map(|b| b.list());
+            let infos: Vec<Value> = lists
+                .iter()
+                .flatten()
+                .map(|l| {
+                    json!({
+                        "path": l.path,
+                        "entries": l.len(),
+                        "blocked": l.blocked.load(Ordering::Relaxed),
+                    })
+                })
+                .collect();
+            (200, json!(infos))
+        }
+        ("DELETE", ["sessions", id]) => match id.parse() {
+            Ok(id) if sessions.kill(id) => {
+                warn!("[Admin] 终止会话 #{}", id);
+                (200, json!({ "killed": 1 }))
+            }
+            Ok(_) => (404, json!({ "error": "session not found" })),
+            Err(_) => (400, json!({ "error": "invalid session id" })),
+        },
+        ("DELETE", ["users", user, "sessions"]) => {
+            let killed = sessions.kill_user(user);
+            warn!("[Admin] 终止用户 {} 的 {} 个会话", user, killed);
+            (200, json!({ "killed": killed }))
+        }
+        _ => (404, json!({ "error": "not found" })),
+    }
+}
+
+async fn respond(socket: &mut TcpStream, status: u16, body: &Value) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        413 => "Payload Too Large",
+        _ => "Error",
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
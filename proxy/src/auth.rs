@@ -1,10 +1,17 @@
 // src/auth.rs
+use crate::blocklist::Blocklists;
+use crate::config::IpNet;
 use crate::consts::*;
+use crate::pool::ConnectionPool;
+use crate::session::SessionRegistry;
+use crate::upstream::UpstreamPool;
 use std::error::Error;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::{debug, info, warn};
 
 #[derive(Debug, Clone)]
@@ -15,12 +22,101 @@ pub struct User {
 // 简单的用户配置结构
 #[derive(Debug, Clone)]
 pub struct UserConfig {
-    pub user: Option<User>,
+    pub auth: AuthRegistry,
     pub timeout: u8,
+    pub sessions: Arc<SessionRegistry>,
+    pub upstreams: Option<Arc<UpstreamPool>>,
+    pub blocklists: Option<Arc<Blocklists>>,
+    pub pool: Option<Arc<ConnectionPool>>,
 }
 
-pub async fn perform_password_auth(
-    socket: &mut TcpStream,
+// 认证子协商使用的连接，屏蔽 TCP / Unix 套接字的差异
+pub trait AuthStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AuthStream for T {}
+
+pub type AuthFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Option<String>, Box<dyn Error>>> + Send + 'a>>;
+
+/// SOCKS5 认证方式，由 AuthRegistry 按方法字节 (METHOD_*) 管理
+pub trait AuthMethod: Debug + Send + Sync {
+    fn method(&self) -> u8;
+
+    /// 该来源地址能否使用此方式，unix 套接字等没有来源 IP 时为 None
+    fn allows(&self, _peer: Option<IpAddr>) -> bool {
+        true
+    }
+
+    /// 回复方法选择后进行子协商，成功时返回用户名
+    fn authenticate<'a>(&'a self, socket: &'a mut dyn AuthStream) -> AuthFuture<'a>;
+}
+
+// 免认证，可限定来源地址白名单
+#[derive(Debug)]
+pub struct NoAuth {
+    pub allow: Option<Vec<IpNet>>,
+}
+
+impl AuthMethod for NoAuth {
+    fn method(&self) -> u8 {
+        METHOD_NO_AUTH
+    }
+
+    fn allows(&self, peer: Option<IpAddr>) -> bool {
+        match (&self.allow, peer) {
+            (None, _) => true,
+            (Some(allow), Some(ip)) => allow.iter().any(|net| net.contains(ip)),
+            (Some(_), None) => false,
+        }
+    }
+
+    fn authenticate<'a>(&'a self, _socket: &'a mut dyn AuthStream) -> AuthFuture<'a> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+// 用户名/密码认证 (RFC 1929)
+#[derive(Debug)]
+pub struct PasswordAuth {
+    pub user: User,
+}
+
+impl AuthMethod for PasswordAuth {
+    fn method(&self) -> u8 {
+        METHOD_PASSWORD
+    }
+
+    fn authenticate<'a>(&'a self, socket: &'a mut dyn AuthStream) -> AuthFuture<'a> {
+        Box::pin(async move {
+            perform_password_auth(socket, &self.user).await?;
+            Ok(Some(self.user.username.clone()))
+        })
+    }
+}
+
+// 已启用的认证方式，注册顺序即服务端偏好顺序
+#[derive(Debug, Clone, Default)]
+pub struct AuthRegistry {
+    methods: Vec<Arc<dyn AuthMethod>>,
+}
+
+impl AuthRegistry {
+    /// 注册认证方式，同一方法字节重复注册时替换原有实现
+    pub fn register(&mut self, method: impl AuthMethod + 'static) {
+        self.methods.retain(|m| m.method() != method.method());
+        self.methods.push(Arc::new(method));
+    }
+
+    /// 按服务端偏好顺序，选出客户端提供且允许该来源使用的第一个方式
+    pub fn select(&self, offered: &[u8], peer: Option<IpAddr>) -> Option<&dyn AuthMethod> {
+        self.methods
+            .iter()
+            .find(|m| offered.contains(&m.method()) && m.allows(peer))
+            .map(|m| m.as_ref())
+    }
+}
+
+pub async fn perform_password_auth<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(
+    socket: &mut S,
     user_config: &User,
 ) -> Result<(), Box<dyn Error>> {
     // 1. 读取版本号和用户名长度 [VER, ULEN]
@@ -0,0 +1,219 @@
+// src/blocklist.rs
+//
+// 域名/IP 黑名单，支持两种文件格式 (# 开头为注释)：
+//   hosts 格式:  0.0.0.0 ads.example.com tracker.example.com
+//   纯列表格式:  ads.example.com  或  203.0.113.7
+// 域名按后缀匹配：列表中的 example.com 同时屏蔽 a.example.com
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::lookup_host;
+use tokio::time::{Instant, timeout_at};
+use tracing::{info, warn};
+
+use crate::protocol::split_host_port;
+
+// hosts 文件里指向本机的常见条目，不当作黑名单域名
+const HOSTS_IGNORED: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+pub struct Blocklist {
+    pub path: PathBuf,
+    domains: HashSet<String>,
+    ips: HashSet<IpAddr>,
+    modified: Option<SystemTime>,
+    // 重新加载时沿用，保证计数不被清零
+    pub blocked: Arc<AtomicU64>,
+}
+
+impl Blocklist {
+    fn load(path: PathBuf, blocked: Arc<AtomicU64>) -> Result<Self, Box<dyn Error>> {
+        let modified = fs::metadata(&path)?.modified().ok();
+        let content = fs::read_to_string(&path)?;
+
+        let mut domains = HashSet::new();
+        let mut ips = HashSet::new();
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(first) = fields.next() else {
+                continue;
+            };
+
+            match (first.parse::<IpAddr>(), fields.next()) {
+                // hosts 格式，第一列的 IP 只是重定向地址
+                (Ok(_), Some(name)) => {
+                    for name in std::iter::once(name).chain(fields) {
+                        if !HOSTS_IGNORED.contains(&name) {
+                            domains.insert(normalize(name));
+                        }
+                    }
+                }
+                (Ok(ip), None) => {
+                    ips.insert(ip);
+                }
+                (Err(_), _) => {
+                    domains.insert(normalize(first));
+                }
+            }
+        }
+
+        Ok(Blocklist {
+            path,
+            domains,
+            ips,
+            modified,
+            blocked,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len() + self.ips.len()
+    }
+
+    /// 依次查找 a.b.example.com、b.example.com、example.com、com
+    fn contains_domain(&self, domain: &str) -> bool {
+        let domain = normalize(domain);
+        let mut suffix = domain.as_str();
+        loop {
+            if self.domains.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, rest)) => suffix = rest,
+                None => return false,
+            }
+        }
+    }
+
+    fn contains_ip(&self, ip: IpAddr) -> bool {
+        self.ips.contains(&ip) || self.ips.contains(&ip.to_canonical())
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// 黑名单检查不通过的原因
+#[derive(Debug)]
+pub enum Refusal {
+    /// 命中的黑名单
+    Listed(PathBuf),
+    /// 有 IP 条目时域名解析失败或超时，无法确认是否命中
+    Unresolved(io::Error),
+}
+
+#[derive(Default)]
+pub struct Blocklists {
+    lists: RwLock<Vec<Arc<Blocklist>>>,
+}
+
+impl std::fmt::Debug for Blocklists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let paths: Vec<_> = self.list().iter().map(|l| l.path.clone()).collect();
+        f.debug_struct("Blocklists").field("lists", &paths).finish()
+    }
+}
+
+impl Blocklists {
+    pub fn load(paths: Vec<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let mut lists = Vec::new();
+        for path in paths {
+            let list = Blocklist::load(path, Arc::default())?;
+            info!("加载黑名单 {}: {} 条", list.path.display(), list.len());
+            lists.push(Arc::new(list));
+        }
+
+        Ok(Blocklists {
+            lists: RwLock::new(lists),
+        })
+    }
+
+    pub fn list(&self) -> Vec<Arc<Blocklist>> {
+        self.lists.read().unwrap().clone()
+    }
+
+    /// 检查目标域名及其解析结果，命中时返回所在的黑名单。
+    /// 只有列表中有 IP 条目时才解析域名，解析在 deadline 前完成，
+    /// 解析不出来就无法确认，按拒绝处理；
+    /// 放行时返回解析出的地址，供直连时复用，避免重复解析
+    pub async fn check(
+        &self,
+        target: &str,
+        deadline: Instant,
+    ) -> Result<Option<Vec<SocketAddr>>, Refusal> {
+        let Some((host, _)) = split_host_port(target) else {
+            return Ok(None);
+        };
+
+        let lists = self.list();
+        if host.parse::<IpAddr>().is_err() {
+            for list in &lists {
+                if list.contains_domain(host) {
+                    return Err(self.hit(list));
+                }
+            }
+        }
+
+        if lists.iter().all(|list| list.ips.is_empty()) {
+            return Ok(None);
+        }
+
+        let addrs: Vec<SocketAddr> = match timeout_at(deadline, lookup_host(target)).await {
+            Ok(Ok(addrs)) => addrs.collect(),
+            Ok(Err(e)) => return Err(Refusal::Unresolved(e)),
+            Err(_) => {
+                let e = io::Error::new(io::ErrorKind::TimedOut, "解析目标超时");
+                return Err(Refusal::Unresolved(e));
+            }
+        };
+        for list in &lists {
+            if addrs.iter().any(|addr| list.contains_ip(addr.ip())) {
+                return Err(self.hit(list));
+            }
+        }
+
+        Ok(Some(addrs))
+    }
+
+    fn hit(&self, list: &Blocklist) -> Refusal {
+        list.blocked.fetch_add(1, Ordering::Relaxed);
+        Refusal::Listed(list.path.clone())
+    }
+
+    /// 定期检查文件修改时间，变化时重新加载，加载失败保留旧列表
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            for (i, list) in self.list().into_iter().enumerate() {
+                let modified = fs::metadata(&list.path).and_then(|m| m.modified()).ok();
+                if modified == list.modified {
+                    continue;
+                }
+
+                match Blocklist::load(list.path.clone(), list.blocked.clone()) {
+                    Ok(new) => {
+                        info!("重新加载黑名单 {}: {} 条", new.path.display(), new.len());
+                        self.lists.write().unwrap()[i] = Arc::new(new);
+                    }
+                    Err(e) => warn!("重新加载黑名单 {} 失败: {}", list.path.display(), e),
+                }
+            }
+        }
+    }
+}
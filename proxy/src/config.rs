@@ -0,0 +1,109 @@
+// src/config.rs
+use serde::Serialize;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::protocol::split_host_port;
+
+// 端口转发规则: 监听 listen，所有连接固定转发到 target
+// 命令行格式: --forward 0.0.0.0:2222=10.0.0.5:22
+// 反向转发 (--reverse) 时 listen 是 gateway 上的地址，target 由 agent 连接
+#[derive(Debug, Clone, Serialize)]
+pub struct ForwardRule {
+    pub listen: SocketAddr,
+    pub target: String,
+}
+
+impl FromStr for ForwardRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (listen, target) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid forward rule (expect LISTEN=HOST:PORT): {}", s))?;
+
+        let listen = listen
+            .parse()
+            .map_err(|e| format!("invalid listen address {}: {}", listen, e))?;
+
+        Ok(ForwardRule {
+            listen,
+            target: parse_target(target)?,
+        })
+    }
+}
+
+/// 目标地址 HOST:PORT，可以是域名，连接时再解析，这里只检查格式
+pub fn parse_target(target: &str) -> Result<String, String> {
+    match split_host_port(target) {
+        Some(_) => Ok(target.to_string()),
+        None => Err(format!("invalid target address: {}", target)),
+    }
+}
+
+impl fmt::Display for ForwardRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.listen, self.target)
+    }
+}
+
+// 来源地址段，支持单个 IP 或 CIDR (如 10.0.0.0/8、::1)
+#[derive(Debug, Clone, Copy)]
+pub struct IpNet {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("invalid ip {}: {}", addr, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length: {}", s))?,
+            None => max,
+        };
+
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for IpNet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
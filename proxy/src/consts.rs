@@ -18,8 +18,9 @@
 
 pub const SOCKS_VERSION: u8 = 0x05;
 
-// auth methods
+// auth methods (GSSAPI 未实现)
 pub const METHOD_NO_AUTH: u8 = 0x00;
+#[allow(dead_code)]
 pub const METHOD_GASSAPI: u8 = 0x01;
 pub const METHOD_PASSWORD: u8 = 0x02;
 pub const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
@@ -39,11 +40,14 @@ pub const ATYP_IPV6: u8 = 0x04;
 
 // SOCKS5 响应码 (REP)
 pub const REP_SUCCESS: u8 = 0x00;
+#[allow(dead_code)]
 pub const REP_GENERAL_FAILURE: u8 = 0x01;
 pub const REP_CONNECTION_NOT_ALLOWED: u8 = 0x02;
 pub const REP_NETWORK_UNREACHABLE: u8 = 0x03;
 pub const REP_HOST_UNREACHABLE: u8 = 0x04;
 pub const REP_CONNECTION_REFUSED: u8 = 0x05;
 pub const REP_TTL_EXPIRED: u8 = 0x06;
+#[allow(dead_code)]
 pub const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+#[allow(dead_code)]
 pub const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
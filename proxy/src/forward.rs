@@ -0,0 +1,30 @@
+// src/forward.rs
+use std::error::Error;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::auth::UserConfig;
+use crate::config::ForwardRule;
+use crate::handler;
+
+pub async fn serve(
+    listener: TcpListener,
+    rule: ForwardRule,
+    config: Arc<UserConfig>,
+) -> Result<(), Box<dyn Error>> {
+    info!("Forward running on {}", rule);
+    let rule = Arc::new(rule);
+
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let config_clone = config.clone();
+        let rule = rule.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handler::relay(socket, &rule.target, config_clone.as_ref()).await {
+                error!("[Error] forward {} from {:?} : {}", rule.listen, addr, e);
+            }
+        });
+    }
+}
@@ -1,16 +1,38 @@
 use std::error::Error;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
+#[cfg(not(target_os = "linux"))]
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::time::timeout;
+use tokio::time::{Instant, timeout_at};
 use tracing::{debug, error, info, warn};
 
 // 引入我们封装好的模块
-use crate::auth::{self, UserConfig};
+use crate::auth::UserConfig;
+use crate::blocklist::Refusal;
 use crate::consts::*;
 use crate::protocol::SocksRequest;
-
-pub async fn process(mut socket: TcpStream, config: &UserConfig) -> Result<(), Box<dyn Error>> {
+use crate::session::{Metered, Session};
+use crate::upstream::UpstreamLease;
+
+// 客户端连接：TCP 或 Unix 域套接字，Linux 下需要支持 splice
+#[cfg(target_os = "linux")]
+pub trait ClientStream: tokio_splice::Stream + Unpin + Send {}
+#[cfg(target_os = "linux")]
+impl<T: tokio_splice::Stream + Unpin + Send> ClientStream for T {}
+
+#[cfg(not(target_os = "linux"))]
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+#[cfg(not(target_os = "linux"))]
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for T {}
+
+pub async fn process<S: ClientStream>(
+    mut socket: S,
+    peer: Option<IpAddr>,
+    config: &UserConfig,
+) -> Result<(), Box<dyn Error>> {
     // ==========================================
     // 阶段 1: 协商 (Handshake)
     // ==========================================
@@ -29,25 +51,20 @@ pub async fn process(mut socket: TcpStream, config: &UserConfig) -> Result<(), B
     let mut methods = vec![0u8; nmethods];
     socket.read_exact(&mut methods).await?;
 
-    let mut should_auth = false;
-
-    if let Some(_user_config) = &config.user {
-        if methods.contains(&METHOD_PASSWORD) {
-            should_auth = true;
-            socket.write_all(&[SOCKS_VERSION, METHOD_PASSWORD]).await?;
-        } else {
+    // 按服务端偏好选择认证方式
+    let username = match config.auth.select(&methods, peer) {
+        Some(method) => {
+            socket.write_all(&[SOCKS_VERSION, method.method()]).await?;
+            method.authenticate(&mut socket).await?
+        }
+        None => {
             socket
                 .write_all(&[SOCKS_VERSION, METHOD_NO_ACCEPTABLE])
                 .await?;
-            return Err("client don't support auth".into());
+            return Err(format!("no acceptable auth method: {:?}", methods).into());
         }
-    } else {
-        socket.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).await?;
-    }
+    };
 
-    if should_auth {
-        auth::perform_password_auth(&mut socket, config.user.as_ref().unwrap()).await?;
-    }
     // ==========================================
     // 阶段 2: 请求 (Request) - 【核心重构点】
     // ==========================================
@@ -66,98 +83,172 @@ pub async fn process(mut socket: TcpStream, config: &UserConfig) -> Result<(), B
     // ==========================================
     // 阶段 3: 转发 (Relay)
     // ==========================================
-    let connect_timeout = Duration::from_secs(config.timeout as u64);
-    let server_socket_result = timeout(connect_timeout, TcpStream::connect(&target)).await;
-
-    let mut server_socket = match server_socket_result {
-        Err(_) => {
-            warn!("连接目标超时 ({}s): {}", config.timeout, target);
-            // 返回 0x04 (Host Unreachable) 或者 0x06 (TTL Expired)
-            let reply = [
-                SOCKS_VERSION,
-                REP_TTL_EXPIRED,
-                0x00,
-                ATYP_IPV4,
-                0,
-                0,
-                0,
-                0,
-                0,
-                0,
-            ];
-            let _ = socket.write_all(&reply).await;
-            return Err("连接目标超时".into());
+    let (mut server_socket, upstream) = match dial(&target, config).await {
+        Ok(dialed) => dialed,
+        Err((rep, e)) => {
+            let _ = reply(&mut socket, rep).await;
+            return Err(e.into());
         }
-        Ok(connect_result) => match connect_result {
-            Ok(s) => s,
-            Err(e) => {
-                let rep = match e.kind() {
-                    std::io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
-                    std::io::ErrorKind::TimedOut => REP_NETWORK_UNREACHABLE,
-                    std::io::ErrorKind::PermissionDenied => REP_CONNECTION_NOT_ALLOWED,
-                    _ => REP_HOST_UNREACHABLE,
-                };
-                error!("目标主机连接失败：{}({})", target, e);
-
-                let replay = [SOCKS_VERSION, rep, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0];
-
-                let _ = socket.write_all(&replay).await;
-                return Err(e.into());
+    };
+
+    // 告诉客户端连接成功
+    reply(&mut socket, REP_SUCCESS).await?;
+
+    let session = config.sessions.register(username, &target, upstream);
+    serve_session(&mut socket, &mut server_socket, &session, config).await
+}
+
+/// 透明代理等已知目标地址的入口：跳过 SOCKS5 协商，直接连接并转发
+pub async fn relay<S: ClientStream>(
+    mut socket: S,
+    target: &str,
+    config: &UserConfig,
+) -> Result<(), Box<dyn Error>> {
+    info!("Connect to: {}", target);
+
+    let (mut server_socket, upstream) = dial(target, config).await.map_err(|(_, e)| e)?;
+    let session = config.sessions.register(None, target, upstream);
+    serve_session(&mut socket, &mut server_socket, &session, config).await
+}
+
+/// 转发已登记的会话，直到任一方断开或被管理接口终止
+async fn serve_session<S: ClientStream>(
+    client: &mut S,
+    server: &mut TcpStream,
+    session: &Session,
+    config: &UserConfig,
+) -> Result<(), Box<dyn Error>> {
+    let metered = config.sessions.metered;
+
+    tokio::select! {
+        result = transfer(client, server, session, metered) => result,
+        _ = session.killed() => {
+            warn!("会话 #{} 已被管理接口终止: {}", session.id, session.target);
+            Ok(())
+        }
+    }
+}
+
+/// 连接目标地址，配置了上游池时经由上游出口，失败时同时返回对应的 SOCKS5 响应码
+pub async fn dial(
+    target: &str,
+    config: &UserConfig,
+) -> Result<(TcpStream, Option<UpstreamLease>), (u8, std::io::Error)> {
+    // 黑名单解析和连接共用一个期限，总耗时不超过 --timeout
+    let deadline = Instant::now() + Duration::from_secs(config.timeout as u64);
+
+    let mut resolved = None;
+    if let Some(blocklists) = &config.blocklists {
+        match blocklists.check(target, deadline).await {
+            Ok(addrs) => resolved = addrs,
+            Err(Refusal::Listed(list)) => {
+                warn!("目标 {} 命中黑名单 {}", target, list.display());
+                let e = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "目标在黑名单中");
+                return Err((REP_CONNECTION_NOT_ALLOWED, e));
             }
+            Err(Refusal::Unresolved(e)) => {
+                warn!("无法解析目标 {}，不能确认是否在黑名单中: {}", target, e);
+                return Err((REP_HOST_UNREACHABLE, e));
+            }
+        }
+    }
+
+    let result = match &config.upstreams {
+        Some(pool) => match pool.connect(target, deadline).await {
+            Ok((s, lease)) => {
+                info!("{} 经由上游 {}", target, lease.name());
+                Ok((s, Some(lease)))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                warn!("经由上游连接目标超时 ({}s): {}", config.timeout, target);
+                return Err((REP_TTL_EXPIRED, e));
+            }
+            Err(e) => Err(e),
         },
+        None => {
+            let pooled = match &config.pool {
+                Some(pool) => pool.take(target).await,
+                None => None,
+            };
+            if let Some(stream) = pooled {
+                return Ok((stream, None));
+            }
+
+            // 黑名单检查时已经解析过的，直接使用解析结果
+            let connect = async {
+                match &resolved {
+                    Some(addrs) => TcpStream::connect(&addrs[..]).await,
+                    None => TcpStream::connect(target).await,
+                }
+            };
+            match timeout_at(deadline, connect).await {
+                Err(_) => {
+                    warn!("连接目标超时 ({}s): {}", config.timeout, target);
+                    // 返回 0x04 (Host Unreachable) 或者 0x06 (TTL Expired)
+                    let e = std::io::Error::new(std::io::ErrorKind::TimedOut, "连接目标超时");
+                    return Err((REP_TTL_EXPIRED, e));
+                }
+                Ok(result) => result.map(|s| (s, None)),
+            }
+        }
     };
 
-    // 告诉客户端连接成功
-    let reply = [
-        SOCKS_VERSION,
-        REP_SUCCESS,
-        0x00,
-        ATYP_IPV4,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-    ];
-    socket.write_all(&reply).await?;
-
-    transfer(&mut socket, &mut server_socket).await?;
-
-    Ok(())
+    result.map_err(|e| {
+        let rep = match e.kind() {
+            std::io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+            std::io::ErrorKind::TimedOut => REP_NETWORK_UNREACHABLE,
+            std::io::ErrorKind::PermissionDenied => REP_CONNECTION_NOT_ALLOWED,
+            _ => REP_HOST_UNREACHABLE,
+        };
+        error!("目标主机连接失败：{}({})", target, e);
+        (rep, e)
+    })
 }
 
-async fn transfer(client: &mut TcpStream, server: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+async fn reply<S: ClientStream>(socket: &mut S, rep: u8) -> std::io::Result<()> {
+    let reply = [SOCKS_VERSION, rep, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+    socket.write_all(&reply).await
+}
+
+// metered 为 false 时 Linux 下走 splice 零拷贝，流量只在结束时计入会话
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+async fn transfer<S: ClientStream>(
+    client: &mut S,
+    server: &mut TcpStream,
+    session: &Session,
+    metered: bool,
+) -> Result<(), Box<dyn Error>> {
     #[cfg(target_os = "linux")]
-    {
+    if !metered {
         use tokio_splice::zero_copy_bidirectional;
 
         // splice 需要文件描述符，tokio 的 TcpStream 实现了 AsRawFd
-        match zero_copy_bidirectional(client, server).await {
+        return match zero_copy_bidirectional(client, server).await {
             Ok((up, down)) => {
                 debug!("Splice 传输完成: 上行 {}b, 下行 {}b", up, down);
+                session.up.fetch_add(up, Ordering::Relaxed);
+                session.down.fetch_add(down, Ordering::Relaxed);
                 Ok(())
             }
             Err(e) => {
                 error!("Splice 传输错误: {}", e);
                 Err(e.into())
             }
-        }
+        };
     }
 
-    #[cfg(not(target_os = "linux"))]
-    {
-        // 非 Linux (macOS/Windows) 使用普通的用户态拷贝
-        match tokio::io::copy_bidirectional(client, server).await {
-            Ok((up, down)) => {
-                debug!("Copy 传输完成: 上行 {}b, 下行 {}b", up, down);
-                Ok(())
-            }
-            Err(e) => {
-                // copy_bidirectional 有时在断开时会报 ConnectionReset，这其实不算严重错误
-                debug!("Copy 传输中断: {}", e);
-                Ok(())
-            }
+    // 非 Linux (macOS/Windows) 或需要实时统计流量时，使用普通的用户态拷贝
+    let mut client = Metered::new(client, &session.up);
+    let mut server = Metered::new(server, &session.down);
+    match tokio::io::copy_bidirectional(&mut client, &mut server).await {
+        Ok((up, down)) => {
+            debug!("Copy 传输完成: 上行 {}b, 下行 {}b", up, down);
+            Ok(())
+        }
+        Err(e) => {
+            // copy_bidirectional 有时在断开时会报 ConnectionReset，这其实不算严重错误
+            debug!("Copy 传输中断: {}", e);
+            Ok(())
         }
     }
 }
@@ -1,21 +1,46 @@
 // src/main.rs
 use clap::Parser;
+use serde::Serialize;
 use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
 use tracing::{Level, error, info};
 
+mod admin;
 mod auth;
+mod blocklist;
+mod config;
 mod consts;
+mod forward;
 mod handler;
+mod pool;
 mod protocol;
+mod session;
+#[cfg(target_os = "linux")]
+mod transparent;
+mod tunnel;
+#[cfg(unix)]
+mod unix;
+mod upstream;
 
 use auth::UserConfig;
 
-use crate::auth::User;
+use crate::auth::{AuthRegistry, NoAuth, PasswordAuth, User};
+use crate::blocklist::Blocklists;
+use crate::pool::ConnectionPool;
+use crate::session::SessionRegistry;
+use crate::upstream::UpstreamPool;
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[derive(Parser, Debug, Serialize)]
+#[command(version, about, long_about = None, args_override_self = true)]
 struct Args {
+    /// 配置文件 (TOML，键名与长参数相同)，命令行参数优先；
+    /// 转发规则写成数组，如 forward = ["0.0.0.0:2222=10.0.0.5:22"]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     /// 监听地址
     #[arg(short, long, default_value = "127.0.0.1")]
     ip: String,
@@ -30,11 +55,102 @@ struct Args {
 
     /// 认证密码 (可选，必须配合 user 使用)
     #[arg(long)]
+    #[serde(skip)]
     pass: Option<String>,
 
+    /// 免认证的来源地址段 (IP 或 CIDR)，可重复指定；未指定 user 时其余来源将被拒绝
+    #[arg(long)]
+    no_auth_allow: Vec<config::IpNet>,
+
     /// 超时时间
     #[arg(long, default_value_t = 5)]
     timeout: u8,
+
+    /// 管理接口监听地址 (如 127.0.0.1:9090)，开启后实时统计会话流量
+    #[arg(long)]
+    admin: Option<std::net::SocketAddr>,
+
+    /// 出口上游 bind:IP (本地源地址) 或 socks5://HOST:PORT，可重复指定
+    #[arg(long)]
+    upstream: Vec<upstream::UpstreamKind>,
+
+    /// 上游选择策略
+    #[arg(long, value_enum, default_value_t = upstream::Strategy::RoundRobin)]
+    upstream_strategy: upstream::Strategy,
+
+    /// 上游健康检查间隔 (秒)
+    #[arg(long, default_value_t = 10)]
+    upstream_check: u64,
+
+    /// 黑名单文件 (hosts 格式或每行一个域名/IP)，可重复指定
+    #[arg(long)]
+    blocklist: Vec<PathBuf>,
+
+    /// 黑名单文件变更检查间隔 (秒)
+    #[arg(long, default_value_t = 30)]
+    blocklist_reload: u64,
+
+    /// 预连接的热点目标 HOST:PORT，可重复指定 (仅直连时生效)
+    #[arg(long, value_parser = config::parse_target)]
+    warm: Vec<String>,
+
+    /// 每个热点目标保持的空闲连接数
+    #[arg(long, default_value_t = 4)]
+    warm_size: usize,
+
+    /// 预连接最长空闲时间 (秒)，超过后丢弃重建
+    #[arg(long, default_value_t = 30)]
+    warm_idle: u64,
+
+    /// 静态端口转发规则 LISTEN=HOST:PORT，可重复指定
+    #[arg(long)]
+    forward: Vec<config::ForwardRule>,
+
+    /// 反向隧道 gateway 监听地址 (如 0.0.0.0:7000)，接受 agent 连入并代为监听它的 --reverse 端口
+    #[arg(long, requires = "tunnel_secret")]
+    tunnel_listen: Option<std::net::SocketAddr>,
+
+    /// 作为 agent 连接的 gateway 地址 HOST:PORT，断开后自动重连
+    #[arg(long, requires_all = ["tunnel_secret", "reverse"], value_parser = config::parse_target)]
+    tunnel_server: Option<String>,
+
+    /// 反向隧道口令，gateway 与 agent 必须一致
+    #[arg(long)]
+    #[serde(skip)]
+    tunnel_secret: Option<String>,
+
+    /// 反向转发规则 REMOTE_LISTEN=HOST:PORT，在 gateway 上监听，由本机连接目标，可重复指定
+    #[arg(long, requires = "tunnel_server")]
+    reverse: Vec<config::ForwardRule>,
+
+    /// 监听 Unix 域套接字路径 (设置后不再监听 TCP)
+    #[arg(long)]
+    unix: Option<PathBuf>,
+
+    /// Unix 套接字文件权限，八进制 (如 660)；不指定时只有属主可以连接
+    #[cfg(unix)]
+    #[arg(long, requires = "unix", value_parser = unix::parse_mode)]
+    unix_mode: Option<u32>,
+
+    /// Unix 套接字文件属主 uid
+    #[cfg(unix)]
+    #[arg(long, requires = "unix")]
+    unix_uid: Option<u32>,
+
+    /// Unix 套接字文件属组 gid
+    #[cfg(unix)]
+    #[arg(long, requires = "unix")]
+    unix_gid: Option<u32>,
+
+    /// 透明代理监听地址 (如 0.0.0.0:12345)，与 SOCKS5 监听同时运行
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    transparent: Option<std::net::SocketAddr>,
+
+    /// 透明代理模式
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_enum, requires = "transparent", default_value_t = transparent::Mode::Redirect)]
+    transparent_mode: transparent::Mode,
 }
 
 #[tokio::main]
@@ -45,35 +161,172 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with_thread_ids(true)
         .init();
 
-    let args = Args::parse();
-
-    let mut config = UserConfig {
-        user: None,
-        timeout: args.timeout,
+    let args = match config_args::args() {
+        Ok(args) => Args::parse_from(args),
+        Err(e) => {
+            error!("加载配置文件失败: {}", e);
+            std::process::exit(2);
+        }
     };
-    let timeout = args.timeout;
+    let settings = serde_json::to_value(&args)?;
+    let sessions = Arc::new(SessionRegistry::new(args.admin.is_some()));
+
+    let mut auth = AuthRegistry::default();
+    // 白名单来源优先免认证，其余来源走密码认证
+    if !args.no_auth_allow.is_empty() {
+        info!("no auth for: {:?}", args.no_auth_allow);
+        auth.register(NoAuth {
+            allow: Some(args.no_auth_allow.clone()),
+        });
+    }
     if let Some(user) = args.user {
         if let Some(pass) = args.pass {
             info!("use auth,user:{}", user);
-            config = UserConfig {
-                user: Some(User {
+            auth.register(PasswordAuth {
+                user: User {
                     username: user,
                     password: pass,
-                }),
-                timeout,
-            };
+                },
+            });
         } else {
             error!("no password");
             std::process::exit(1);
         }
-    } else {
+    } else if args.no_auth_allow.is_empty() {
         info!("running in No_auth");
+        auth.register(NoAuth { allow: None });
     }
 
-    let config = std::sync::Arc::new(config);
+    let upstreams = if args.upstream.is_empty() {
+        None
+    } else {
+        info!(
+            "upstreams ({:?}): {:?}",
+            args.upstream_strategy, args.upstream
+        );
+        let pool = Arc::new(UpstreamPool::new(args.upstream, args.upstream_strategy));
+        let interval = Duration::from_secs(args.upstream_check.max(1));
+        tokio::spawn(pool.clone().health_check(interval));
+        Some(pool)
+    };
+
+    let blocklists = if args.blocklist.is_empty() {
+        None
+    } else {
+        let lists = Arc::new(Blocklists::load(args.blocklist)?);
+        let interval = Duration::from_secs(args.blocklist_reload.max(1));
+        tokio::spawn(lists.clone().watch(interval));
+        Some(lists)
+    };
+
+    let pool = if args.warm.is_empty() {
+        None
+    } else {
+        info!("warm targets: {:?}", args.warm);
+        let max_idle = Duration::from_secs(args.warm_idle);
+        let pool = Arc::new(ConnectionPool::new(args.warm, args.warm_size, max_idle));
+        let pool_clone = pool.clone();
+        let connect_timeout = Duration::from_secs(args.timeout as u64);
+        tokio::spawn(async move { pool_clone.run(connect_timeout).await });
+        Some(pool)
+    };
+
+    let config = UserConfig {
+        auth,
+        timeout: args.timeout,
+        sessions,
+        upstreams,
+        blocklists,
+        pool,
+    };
+
+    let config = Arc::new(config);
+
+    if let Some(addr) = args.admin {
+        let listener = TcpListener::bind(addr).await?;
+        let state = Arc::new(admin::AdminState {
+            config: config.clone(),
+            settings,
+            started: Instant::now(),
+        });
+
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(listener, state).await {
+                error!("管理接口监听退出: {}", e);
+            }
+        });
+    }
+
+    for rule in args.forward {
+        let listener = TcpListener::bind(rule.listen).await?;
+        let config_clone = config.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = forward::serve(listener, rule, config_clone).await {
+                error!("端口转发监听退出: {}", e);
+            }
+        });
+    }
+
+    if let Some(addr) = args.tunnel_listen {
+        let listener = TcpListener::bind(addr).await?;
+        let secret = args.tunnel_secret.clone().unwrap_or_default();
+        let config_clone = config.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = tunnel::serve_gateway(listener, secret, config_clone).await {
+                error!("反向隧道监听退出: {}", e);
+            }
+        });
+    }
+
+    if let Some(server) = args.tunnel_server {
+        let secret = args.tunnel_secret.unwrap_or_default();
+        tokio::spawn(tunnel::serve_agent(
+            server,
+            secret,
+            args.reverse,
+            config.clone(),
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(addr) = args.transparent {
+        let mode = args.transparent_mode;
+        let listener = transparent::bind(addr, mode)?;
+        let config_clone = config.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = transparent::serve(listener, mode, config_clone).await {
+                error!("透明代理监听退出: {}", e);
+            }
+        });
+    }
+
+    if let Some(path) = args.unix {
+        #[cfg(unix)]
+        {
+            let unix_config = unix::UnixSocketConfig {
+                path,
+                mode: args.unix_mode,
+                uid: args.unix_uid,
+                gid: args.unix_gid,
+            };
+            return serve_unix(&unix_config, config).await;
+        }
+        #[cfg(not(unix))]
+        {
+            error!("当前平台不支持 Unix 域套接字: {}", path.display());
+            std::process::exit(1);
+        }
+    }
 
     let addr = format!("{}:{}", args.ip, args.port);
-    let listener = TcpListener::bind(&addr).await?;
+    serve_tcp(&addr, config).await
+}
+
+async fn serve_tcp(addr: &str, config: Arc<UserConfig>) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr).await?;
     info!("SOCKS5 Server running on {}", addr);
 
     loop {
@@ -81,9 +334,30 @@ async fn main() -> Result<(), Box<dyn Error>> {
         let config_clone = config.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = handler::process(socket, config_clone.as_ref()).await {
+            let peer = Some(addr.ip());
+            if let Err(e) = handler::process(socket, peer, config_clone.as_ref()).await {
                 error!("[Error] from {:?} : {}", addr, e);
             }
         });
     }
 }
+
+#[cfg(unix)]
+async fn serve_unix(
+    unix_config: &unix::UnixSocketConfig,
+    config: Arc<UserConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = unix::bind(unix_config)?;
+    info!("SOCKS5 Server running on {}", unix_config.path.display());
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let config_clone = config.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handler::process(socket, None, config_clone.as_ref()).await {
+                error!("[Error] from unix socket : {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,97 @@
+// src/pool.rs
+//
+// 热点目标预连接池：提前建立好 TCP 连接，CONNECT 请求到来时直接取用，省掉握手延迟
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+// 没有取用时也定期补充和清理过期连接
+const REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct ConnectionPool {
+    idle: HashMap<String, Mutex<VecDeque<(TcpStream, Instant)>>>,
+    size: usize,
+    max_idle: Duration,
+    refill: Notify,
+}
+
+impl ConnectionPool {
+    pub fn new(targets: Vec<String>, size: usize, max_idle: Duration) -> Self {
+        let idle = targets
+            .into_iter()
+            .map(|target| (target.to_ascii_lowercase(), Mutex::new(VecDeque::new())))
+            .collect();
+
+        ConnectionPool {
+            idle,
+            size,
+            max_idle,
+            refill: Notify::new(),
+        }
+    }
+
+    /// 取出一条仍然可用的预连接，目标不在池中或暂无空闲连接时返回 None
+    pub async fn take(&self, target: &str) -> Option<TcpStream> {
+        let queue = self.idle.get(&target.to_ascii_lowercase())?;
+
+        loop {
+            let (stream, created) = queue.lock().unwrap().pop_front()?;
+            self.refill.notify_one();
+
+            if created.elapsed() < self.max_idle && is_alive(&stream).await {
+                debug!("复用预连接: {}", target);
+                return Some(stream);
+            }
+        }
+    }
+
+    /// 后台维持每个目标 size 条空闲连接
+    pub async fn run(&self, connect_timeout: Duration) {
+        loop {
+            for (target, queue) in &self.idle {
+                let missing = {
+                    let mut queue = queue.lock().unwrap();
+                    queue.retain(|(_, created)| created.elapsed() < self.max_idle);
+                    self.size.saturating_sub(queue.len())
+                };
+
+                for _ in 0..missing {
+                    match timeout(connect_timeout, TcpStream::connect(target.as_str())).await {
+                        Ok(Ok(stream)) => {
+                            queue.lock().unwrap().push_back((stream, Instant::now()));
+                        }
+                        Ok(Err(e)) => {
+                            warn!("预连接 {} 失败: {}", target, e);
+                            break;
+                        }
+                        Err(_) => {
+                            warn!("预连接 {} 超时", target);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = self.refill.notified() => {}
+                _ = tokio::time::sleep(REFILL_INTERVAL) => {}
+            }
+        }
+    }
+}
+
+// 对端已关闭或出错的连接不再使用；对端先发数据的协议 (如 SSH) peek 不会消费数据
+async fn is_alive(stream: &TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    // 零超时：只轮询一次，没有数据可读说明连接正常空闲
+    match timeout(Duration::ZERO, stream.peek(&mut buf)).await {
+        Err(_) => true,
+        Ok(Ok(n)) => n > 0,
+        Ok(Err(_)) => false,
+    }
+}
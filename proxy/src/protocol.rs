@@ -1,8 +1,7 @@
 use std::error::Error;
 use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr};
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::consts::*;
 
@@ -25,13 +24,13 @@ impl fmt::Display for SocksRequest {
         match &self.address {
             Address::IpV4(ip) => write!(f, "{}:{}", ip, self.port),
             Address::Domain(domain) => write!(f, "{}:{}", domain, self.port),
-            Address::IpV6(ip) => write!(f, "[{}],{}", ip, self.port),
+            Address::IpV6(ip) => write!(f, "[{}]:{}", ip, self.port),
         }
     }
 }
 
 impl SocksRequest {
-    pub async fn read_from(socket: &mut TcpStream) -> Result<Self, Box<dyn Error>> {
+    pub async fn read_from<R: AsyncRead + Unpin>(socket: &mut R) -> Result<Self, Box<dyn Error>> {
         let mut head = [0u8; 4];
         socket.read_exact(&mut head).await?;
 
@@ -78,3 +77,13 @@ impl SocksRequest {
         Ok(SocksRequest { cmd, address, port })
     }
 }
+
+/// 拆分 "host:port" / "[v6]:port" 形式的目标地址
+pub fn split_host_port(target: &str) -> Option<(&str, u16)> {
+    let (host, port) = target.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host, port.parse().ok()?))
+}
@@ -0,0 +1,195 @@
+// src/session.rs
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+use crate::upstream::UpstreamLease;
+
+// 一条正在转发的连接
+pub struct Session {
+    pub id: u64,
+    pub user: Option<String>,
+    pub target: String,
+    // 经由的上游出口，持有期间计入上游连接数
+    pub upstream: Option<UpstreamLease>,
+    pub started: SystemTime,
+    // 客户端 -> 目标
+    pub up: AtomicU64,
+    // 目标 -> 客户端
+    pub down: AtomicU64,
+    kill: Notify,
+}
+
+impl Session {
+    /// 等待管理接口终止该会话
+    pub async fn killed(&self) {
+        self.kill.notified().await;
+    }
+
+    pub fn started_secs(&self) -> u64 {
+        self.started
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+// 活跃会话表，由 handler 注册，admin 接口查询和终止
+#[derive(Default)]
+pub struct SessionRegistry {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Arc<Session>>>,
+    // 为 true 时走用户态拷贝以实时统计流量，否则只在连接结束时累计 (splice)
+    pub metered: bool,
+}
+
+impl fmt::Debug for SessionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionRegistry")
+            .field("active", &self.len())
+            .field("metered", &self.metered)
+            .finish()
+    }
+}
+
+impl SessionRegistry {
+    pub fn new(metered: bool) -> Self {
+        SessionRegistry {
+            metered,
+            ..Default::default()
+        }
+    }
+
+    /// 注册会话，返回的 guard 被 drop 时自动注销
+    pub fn register(
+        &self,
+        user: Option<String>,
+        target: &str,
+        upstream: Option<UpstreamLease>,
+    ) -> SessionGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Arc::new(Session {
+            id,
+            user,
+            target: target.to_string(),
+            upstream,
+            started: SystemTime::now(),
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+            kill: Notify::new(),
+        });
+        self.sessions.lock().unwrap().insert(id, session.clone());
+
+        SessionGuard {
+            registry: self,
+            session,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        let mut sessions: Vec<_> = self.sessions.lock().unwrap().values().cloned().collect();
+        sessions.sort_by_key(|s| s.id);
+        sessions
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(session) => {
+                session.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 终止某个用户的全部会话，返回终止的数量
+    pub fn kill_user(&self, user: &str) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        let mut count = 0;
+        for session in sessions.values() {
+            if session.user.as_deref() == Some(user) {
+                session.kill.notify_one();
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+pub struct SessionGuard<'a> {
+    registry: &'a SessionRegistry,
+    session: Arc<Session>,
+}
+
+impl std::ops::Deref for SessionGuard<'_> {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        self.registry
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&self.session.id);
+    }
+}
+
+// 统计读取字节数的流包装，用于用户态拷贝时实时更新会话流量
+pub struct Metered<'a, S> {
+    inner: S,
+    counter: &'a AtomicU64,
+}
+
+impl<'a, S> Metered<'a, S> {
+    pub fn new(inner: S, counter: &'a AtomicU64) -> Self {
+        Metered { inner, counter }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        self.counter.fetch_add(n as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
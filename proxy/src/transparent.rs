@@ -0,0 +1,185 @@
+// src/transparent.rs
+//
+// 透明代理 (仅 Linux)，需要配合 iptables 使用：
+//   REDIRECT: iptables -t nat -A PREROUTING -p tcp -j REDIRECT --to-ports <port>
+//   TPROXY:   iptables -t mangle -A PREROUTING -p tcp -j TPROXY --on-port <port> --tproxy-mark 1
+use std::error::Error;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tracing::{error, info};
+
+use crate::auth::UserConfig;
+use crate::handler;
+
+// <linux/netfilter_ipv6/ip6_tables.h>，libc 中没有导出
+const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// nat 表 REDIRECT，通过 SO_ORIGINAL_DST 取回原始目标
+    Redirect,
+    /// mangle 表 TPROXY，监听 socket 需要 IP_TRANSPARENT，原始目标即本端地址
+    Tproxy,
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    mode: Mode,
+    config: Arc<UserConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let addr = listener.local_addr()?;
+    info!("Transparent ({:?}) proxy running on {}", mode, addr);
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let config_clone = config.clone();
+
+        tokio::spawn(async move {
+            let target = match original_dst(&socket, peer, mode) {
+                Ok(target) if !loops_back(&socket, target, addr, mode) => target,
+                Ok(_) => {
+                    error!("[Error] from {:?} : 直接访问透明代理端口", peer);
+                    return;
+                }
+                Err(e) => {
+                    error!("[Error] from {:?} : 获取原始目标失败: {}", peer, e);
+                    return;
+                }
+            };
+
+            let target = target.to_string();
+            if let Err(e) = handler::relay(socket, &target, config_clone.as_ref()).await {
+                error!("[Error] from {:?} : {}", peer, e);
+            }
+        });
+    }
+}
+
+pub fn bind(addr: SocketAddr, mode: Mode) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+
+    if mode == Mode::Tproxy {
+        let (level, name) = match addr {
+            SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+            SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+        };
+        let on: libc::c_int = 1;
+        // 需要 CAP_NET_ADMIN
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+// 直接连到代理端口时原始目标就是代理自己，转发出去会连回自己。
+// REDIRECT 与本端地址比较 (监听 0.0.0.0 时监听地址比不出来)；
+// TPROXY 的本端地址总是原始目标，只能看端口和监听地址，
+// 监听 0.0.0.0 时目标是本机任一地址才算连回自己
+fn loops_back(socket: &TcpStream, target: SocketAddr, listener: SocketAddr, mode: Mode) -> bool {
+    let target_ip = target.ip().to_canonical();
+    match mode {
+        Mode::Redirect => socket
+            .local_addr()
+            .map(|local| local.ip().to_canonical() == target_ip && local.port() == target.port())
+            .unwrap_or(true),
+        Mode::Tproxy if target.port() != listener.port() => false,
+        Mode::Tproxy if !listener.ip().is_unspecified() => {
+            listener.ip().to_canonical() == target_ip
+        }
+        Mode::Tproxy => {
+            target_ip.is_loopback()
+                || local_ips()
+                    .map(|ips| ips.iter().any(|ip| ip.to_canonical() == target_ip))
+                    .unwrap_or(true)
+        }
+    }
+}
+
+// 本机各网卡上的地址
+fn local_ips() -> io::Result<Vec<IpAddr>> {
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut ips = Vec::new();
+    let mut cursor = list;
+    while let Some(ifa) = unsafe { cursor.as_ref() } {
+        if let Some(addr) = unsafe { ifa.ifa_addr.as_ref() } {
+            match addr.sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                    ips.push(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into());
+                }
+                libc::AF_INET6 => {
+                    let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                    ips.push(Ipv6Addr::from(addr.sin6_addr.s6_addr).into());
+                }
+                _ => {}
+            }
+        }
+        cursor = ifa.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(list) };
+    Ok(ips)
+}
+
+// 双栈 IPv6 监听时 IPv4 客户端的地址形如 ::ffff:1.2.3.4，要按客户端的协议族取原始目标
+fn original_dst(socket: &TcpStream, peer: SocketAddr, mode: Mode) -> io::Result<SocketAddr> {
+    match mode {
+        Mode::Tproxy => socket.local_addr(),
+        Mode::Redirect => match peer.ip().to_canonical() {
+            IpAddr::V4(_) => {
+                let addr: libc::sockaddr_in =
+                    getsockopt(socket, libc::SOL_IP, libc::SO_ORIGINAL_DST)?;
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Ok(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)).into())
+            }
+            IpAddr::V6(_) => {
+                let addr: libc::sockaddr_in6 =
+                    getsockopt(socket, libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST)?;
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                Ok(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), 0, 0).into())
+            }
+        },
+    }
+}
+
+fn getsockopt<T>(socket: &TcpStream, level: libc::c_int, name: libc::c_int) -> io::Result<T> {
+    let mut value: T = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
@@ -0,0 +1,530 @@
+// src/tunnel.rs
+//
+// 反向隧道：内网的代理 (agent) 主动连到公网的代理 (gateway)，由 gateway 代为监听 --reverse 规则里的端口。
+// 连到这些端口的连接都在这一条隧道连接上多路复用转发回 agent，
+// agent 再像静态转发一样连接规则的目标 (同样经过超时、黑名单和上游出口)。
+//
+// +------+--------+--------+----------+
+// | TYPE | STREAM | LENGTH | PAYLOAD  |
+// +------+--------+--------+----------+
+// |  1   |   4    |   4    | LENGTH   |
+// +------+--------+--------+----------+
+//
+// 每条流有发送窗口，对端把数据写出后以 WINDOW 归还，一条慢连接不会堵住整条隧道
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Semaphore, mpsc, watch};
+use tokio::task::JoinSet;
+use tokio::time::{MissedTickBehavior, interval, sleep, timeout};
+use tracing::{error, info, warn};
+
+use crate::auth::UserConfig;
+use crate::config::ForwardRule;
+use crate::handler;
+use crate::session::Session;
+
+// 帧类型 TYPE
+// agent -> gateway: 口令和要监听的地址
+const T_HELLO: u8 = 0x01;
+// gateway -> agent: 监听成功
+const T_READY: u8 = 0x02;
+// gateway -> agent: 拒绝，附原因
+const T_ERROR: u8 = 0x03;
+// gateway -> agent: 新连接，附规则序号
+const T_OPEN: u8 = 0x04;
+const T_DATA: u8 = 0x05;
+// 归还发送窗口，附字节数
+const T_WINDOW: u8 = 0x06;
+// 这个方向的数据已发完
+const T_CLOSE: u8 = 0x07;
+// 中止整条流
+const T_RESET: u8 = 0x08;
+const T_PING: u8 = 0x09;
+
+const HEADER_SIZE: usize = 9;
+const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+const CHUNK_SIZE: usize = 16 * 1024;
+// 每条流未确认数据的上限
+const WINDOW_SIZE: usize = 256 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// 隧道心跳，超时没收到任何帧就认为隧道已断开
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(90);
+// agent 重连间隔从 1 秒开始翻倍，最长 1 分钟
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+struct Packet {
+    kind: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn new(kind: u8, stream: u32, payload: Vec<u8>) -> Self {
+        Packet {
+            kind,
+            stream,
+            payload,
+        }
+    }
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Packet> {
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    let stream = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    if len > MAX_PAYLOAD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "隧道帧长度超出上限",
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Packet::new(header[0], stream, payload))
+}
+
+async fn write_packet<W: AsyncWrite + Unpin>(writer: &mut W, packet: &Packet) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE + packet.payload.len());
+    buf.push(packet.kind);
+    buf.extend_from_slice(&packet.stream.to_be_bytes());
+    buf.extend_from_slice(&(packet.payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&packet.payload);
+    writer.write_all(&buf).await
+}
+
+// 负载内的字符串为 [u16 长度][UTF-8 字节]
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn get_str(buf: &mut &[u8]) -> io::Result<String> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "隧道握手格式错误");
+    if buf.len() < 2 {
+        return Err(invalid());
+    }
+    let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    let s = buf.get(2..2 + len).ok_or_else(invalid)?;
+    let s = String::from_utf8(s.to_vec()).map_err(|_| invalid())?;
+    *buf = &buf[2 + len..];
+    Ok(s)
+}
+
+// 一条隧道连接上的所有流
+struct Mux {
+    out: mpsc::UnboundedSender<Packet>,
+    streams: Mutex<HashMap<u32, Stream>>,
+}
+
+struct Stream {
+    // 收到的数据交给该流的转发任务，None 表示对端已发完
+    data: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    // 发送窗口，单位字节
+    credit: Arc<Semaphore>,
+    reset: watch::Sender<bool>,
+}
+
+// 转发任务持有的一端
+struct Channel {
+    data: mpsc::UnboundedReceiver<Vec<u8>>,
+    credit: Arc<Semaphore>,
+    reset: watch::Receiver<bool>,
+}
+
+impl Mux {
+    fn new() -> (Arc<Mux>, mpsc::UnboundedReceiver<Packet>) {
+        let (out, rx) = mpsc::unbounded_channel();
+        let mux = Mux {
+            out,
+            streams: Mutex::new(HashMap::new()),
+        };
+        (Arc::new(mux), rx)
+    }
+
+    fn send(&self, kind: u8, stream: u32, payload: Vec<u8>) {
+        let _ = self.out.send(Packet::new(kind, stream, payload));
+    }
+
+    fn open(&self, id: u32) -> Channel {
+        let (data_tx, data) = mpsc::unbounded_channel();
+        let credit = Arc::new(Semaphore::new(WINDOW_SIZE));
+        let (reset_tx, reset) = watch::channel(false);
+        let stream = Stream {
+            data: Some(data_tx),
+            credit: credit.clone(),
+            reset: reset_tx,
+        };
+        self.streams.lock().unwrap().insert(id, stream);
+        Channel {
+            data,
+            credit,
+            reset,
+        }
+    }
+
+    // 分发对端发来的流帧；已经结束的流的帧直接丢弃
+    fn dispatch(&self, packet: Packet) {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(stream) = streams.get_mut(&packet.stream) else {
+            return;
+        };
+        match packet.kind {
+            T_DATA => {
+                if let Some(data) = &stream.data {
+                    let _ = data.send(packet.payload);
+                }
+            }
+            T_WINDOW => {
+                if let Ok(n) = <[u8; 4]>::try_from(packet.payload.as_slice()) {
+                    stream.credit.add_permits(u32::from_be_bytes(n) as usize);
+                }
+            }
+            T_CLOSE => stream.data = None,
+            T_RESET => {
+                if let Some(stream) = streams.remove(&packet.stream) {
+                    let _ = stream.reset.send(true);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // 两个方向都结束后注销
+    fn remove(&self, id: u32) {
+        self.streams.lock().unwrap().remove(&id);
+    }
+
+    // 本端中止一条流并通知对端
+    fn reset(&self, id: u32) {
+        self.remove(id);
+        self.send(T_RESET, id, Vec::new());
+    }
+
+    // 隧道断开，中止所有流
+    fn shutdown(&self) {
+        for (_, stream) in self.streams.lock().unwrap().drain() {
+            let _ = stream.reset.send(true);
+        }
+    }
+}
+
+/// 接受 agent 连入，按它的规则代为监听
+pub async fn serve_gateway(
+    listener: TcpListener,
+    secret: String,
+    config: Arc<UserConfig>,
+) -> Result<(), Box<dyn Error>> {
+    info!(
+        "Reverse tunnel gateway running on {}",
+        listener.local_addr()?
+    );
+    let secret = Arc::new(secret);
+
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let config_clone = config.clone();
+        let secret = secret.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = gateway(socket, addr, &secret, config_clone).await {
+                error!("[Error] tunnel from {:?} : {}", addr, e);
+            }
+        });
+    }
+}
+
+/// 连到 gateway 并保持隧道，断开后按退避间隔重连
+pub async fn serve_agent(
+    server: String,
+    secret: String,
+    rules: Vec<ForwardRule>,
+    config: Arc<UserConfig>,
+) {
+    let mut delay = RECONNECT_MIN;
+    loop {
+        match agent(&server, &secret, &rules, &config).await {
+            // 隧道建立过，从最短间隔重新开始
+            Ok(()) => delay = RECONNECT_MIN,
+            Err(e) => warn!(
+                "反向隧道 {} 连接失败: {}，{}s 后重试",
+                server,
+                e,
+                delay.as_secs()
+            ),
+        }
+        sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX);
+    }
+}
+
+async fn gateway(
+    mut socket: TcpStream,
+    agent: SocketAddr,
+    secret: &str,
+    config: Arc<UserConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let hello = timeout(HANDSHAKE_TIMEOUT, read_packet(&mut socket))
+        .await
+        .map_err(|_| "隧道握手超时")??;
+    let mut payload = hello.payload.as_slice();
+    if hello.kind != T_HELLO || get_str(&mut payload)? != secret {
+        refuse(&mut socket, "bad tunnel secret").await;
+        return Err("隧道口令错误".into());
+    }
+
+    // 口令之后是要监听的地址，直到负载结束
+    let mut listeners = Vec::new();
+    while !payload.is_empty() {
+        let listen = get_str(&mut payload)?;
+        let bound = match listen.parse::<SocketAddr>() {
+            Ok(addr) => TcpListener::bind(addr).await,
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        };
+        match bound {
+            Ok(listener) => listeners.push((listen, listener)),
+            Err(e) => {
+                let reason = format!("cannot listen on {}: {}", listen, e);
+                refuse(&mut socket, &reason).await;
+                return Err(reason.into());
+            }
+        }
+    }
+    write_packet(&mut socket, &Packet::new(T_READY, 0, Vec::new())).await?;
+    let names: Vec<&str> = listeners.iter().map(|(name, _)| name.as_str()).collect();
+    info!("反向隧道 {} 已建立，代为监听 {:?}", agent, names);
+
+    let (mux, out) = Mux::new();
+    let next_id = Arc::new(AtomicU32::new(0));
+    let mut accepts = JoinSet::new();
+    for (index, (listen, listener)) in listeners.into_iter().enumerate() {
+        let mux = mux.clone();
+        let config = config.clone();
+        let next_id = next_id.clone();
+        accepts.spawn(async move {
+            loop {
+                let (client, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("[Error] tunnel accept on {} : {}", listen, e);
+                        sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let id = next_id.fetch_add(1, Ordering::Relaxed) + 1;
+                let channel = mux.open(id);
+                mux.send(T_OPEN, id, (index as u16).to_be_bytes().to_vec());
+
+                let mux = mux.clone();
+                let config = config.clone();
+                let target = format!("{} (tunnel {})", listen, agent);
+                tokio::spawn(async move {
+                    info!("Tunnel {} from {:?}", target, peer);
+                    let session = config.sessions.register(None, &target, None);
+                    pipe(&mux, id, client, channel, &session, true).await;
+                });
+            }
+        });
+    }
+
+    // agent 不会发起新流
+    let result = run(socket, &mux, out, |id, _| mux.reset(id)).await;
+    accepts.shutdown().await;
+    mux.shutdown();
+    info!("反向隧道 {} 已断开", agent);
+    result.map_err(Into::into)
+}
+
+async fn agent(
+    server: &str,
+    secret: &str,
+    rules: &[ForwardRule],
+    config: &Arc<UserConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let connect_timeout = Duration::from_secs(config.timeout as u64);
+    let mut socket = timeout(connect_timeout, TcpStream::connect(server))
+        .await
+        .map_err(|_| "连接超时")??;
+
+    let mut hello = Vec::new();
+    put_str(&mut hello, secret);
+    for rule in rules {
+        put_str(&mut hello, &rule.listen.to_string());
+    }
+    write_packet(&mut socket, &Packet::new(T_HELLO, 0, hello)).await?;
+    let reply = timeout(HANDSHAKE_TIMEOUT, read_packet(&mut socket))
+        .await
+        .map_err(|_| "隧道握手超时")??;
+    match reply.kind {
+        T_READY => {}
+        T_ERROR => {
+            let reason = get_str(&mut reply.payload.as_slice())?;
+            return Err(format!("gateway 拒绝: {}", reason).into());
+        }
+        kind => return Err(format!("隧道握手返回未知帧 0x{:02x}", kind).into()),
+    }
+    for rule in rules {
+        info!("Reverse forward running on {} via {}", rule, server);
+    }
+
+    let (mux, out) = Mux::new();
+    let open = |id: u32, payload: Vec<u8>| {
+        let rule = <[u8; 2]>::try_from(payload.as_slice())
+            .ok()
+            .and_then(|index| rules.get(u16::from_be_bytes(index) as usize));
+        let Some(rule) = rule else {
+            mux.reset(id);
+            return;
+        };
+        let channel = mux.open(id);
+        let mux = mux.clone();
+        let config = config.clone();
+        let target = rule.target.clone();
+        tokio::spawn(async move {
+            info!("Connect to: {}", target);
+            match handler::dial(&target, &config).await {
+                Ok((stream, upstream)) => {
+                    let session = config.sessions.register(None, &target, upstream);
+                    pipe(&mux, id, stream, channel, &session, false).await;
+                }
+                Err((_, e)) => {
+                    error!("[Error] reverse forward {} : {}", target, e);
+                    mux.reset(id);
+                }
+            }
+        });
+    };
+    let result = run(socket, &mux, out, open).await;
+    mux.shutdown();
+    warn!("反向隧道 {} 已断开: {:?}", server, result);
+    Ok(())
+}
+
+async fn refuse(socket: &mut TcpStream, reason: &str) {
+    let mut payload = Vec::new();
+    put_str(&mut payload, reason);
+    let _ = write_packet(socket, &Packet::new(T_ERROR, 0, payload)).await;
+}
+
+// 隧道建立后的收发：写任务发送各流的帧和心跳，读循环分发收到的帧，OPEN 交给 open 处理。
+// 返回时隧道已断开
+async fn run(
+    socket: TcpStream,
+    mux: &Mux,
+    mut out: mpsc::UnboundedReceiver<Packet>,
+    open: impl Fn(u32, Vec<u8>),
+) -> io::Result<()> {
+    let (mut reader, mut writer) = socket.into_split();
+
+    let write_loop = async {
+        let mut ping = interval(PING_INTERVAL);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let packet = tokio::select! {
+                packet = out.recv() => match packet {
+                    Some(packet) => packet,
+                    None => return Ok(()),
+                },
+                _ = ping.tick() => Packet::new(T_PING, 0, Vec::new()),
+            };
+            write_packet(&mut writer, &packet).await?;
+        }
+    };
+
+    let read_loop = async {
+        loop {
+            let packet = timeout(PING_TIMEOUT, read_packet(&mut reader))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "隧道心跳超时"))??;
+            match packet.kind {
+                T_PING => {}
+                T_OPEN => open(packet.stream, packet.payload),
+                _ => mux.dispatch(packet),
+            }
+        }
+    };
+
+    tokio::select! {
+        result = write_loop => result,
+        result = read_loop => result,
+    }
+}
+
+// 在本地连接和隧道里的一条流之间转发。client 为 true 时本地连接是发起方 (gateway 上的客户端)，
+// 否则是目标 (agent 连接的目标)，决定流量计入会话的哪个方向
+async fn pipe<S: AsyncRead + AsyncWrite + Unpin>(
+    mux: &Mux,
+    id: u32,
+    local: S,
+    channel: Channel,
+    session: &Session,
+    client: bool,
+) {
+    let Channel {
+        mut data,
+        credit,
+        mut reset,
+    } = channel;
+    let (read, written) = match client {
+        true => (&session.up, &session.down),
+        false => (&session.down, &session.up),
+    };
+    let (mut reader, mut writer) = tokio::io::split(local);
+
+    let outbound = async {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            // 对端还没写出的数据达到窗口上限时等待
+            match credit.acquire_many(n as u32).await {
+                Ok(permit) => permit.forget(),
+                Err(_) => return,
+            }
+            read.fetch_add(n as u64, Ordering::Relaxed);
+            mux.send(T_DATA, id, buf[..n].to_vec());
+        }
+        mux.send(T_CLOSE, id, Vec::new());
+    };
+
+    let inbound = async {
+        while let Some(chunk) = data.recv().await {
+            if writer.write_all(&chunk).await.is_err() {
+                return false;
+            }
+            written.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            mux.send(T_WINDOW, id, (chunk.len() as u32).to_be_bytes().to_vec());
+        }
+        let _ = writer.shutdown().await;
+        true
+    };
+
+    let finished = async {
+        let ((), ok) = tokio::join!(outbound, inbound);
+        ok
+    };
+
+    tokio::select! {
+        ok = finished => match ok {
+            true => mux.remove(id),
+            false => mux.reset(id),
+        },
+        // 对端中止或隧道断开
+        _ = reset.changed() => {}
+        _ = session.killed() => {
+            warn!("会话 #{} 已被管理接口终止: {}", session.id, session.target);
+            mux.reset(id);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+// src/unix.rs
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::UnixListener;
+use tracing::{info, warn};
+
+// Unix 域套接字监听配置
+#[derive(Debug, Clone)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+pub fn bind(config: &UnixSocketConfig) -> Result<UnixListener, Box<dyn Error>> {
+    remove_stale(&config.path)?;
+
+    // 先以只有属主可读写的权限创建 socket 文件，再改成配置的权限和属主，
+    // 避免 chmod/chown 之前按默认 umask 短暂对其他用户开放
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(&config.path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+
+    if let Some(mode) = config.mode {
+        fs::set_permissions(&config.path, fs::Permissions::from_mode(mode))?;
+        info!("socket 文件权限: {:o}", mode);
+    }
+
+    if config.uid.is_some() || config.gid.is_some() {
+        std::os::unix::fs::chown(&config.path, config.uid, config.gid)?;
+        info!("socket 文件属主: uid={:?} gid={:?}", config.uid, config.gid);
+    }
+
+    Ok(listener)
+}
+
+/// 清理上次进程残留的 socket 文件。
+/// 仍有进程在监听时拒绝启动，路径被普通文件占用时也不做删除。
+fn remove_stale(path: &Path) -> Result<(), Box<dyn Error>> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !meta.file_type().is_socket() {
+        return Err(format!("{} 已存在且不是 socket 文件", path.display()).into());
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(format!("{} 正在被其他进程监听", path.display()).into()),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            warn!("删除残留的 socket 文件: {}", path.display());
+            fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid octal mode: {}", s))
+}
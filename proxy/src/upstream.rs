@@ -0,0 +1,355 @@
+// src/upstream.rs
+//
+// 出口上游池：本地源 IP (bind:1.2.3.4) 或上游 SOCKS5 代理 (socks5://host:port)
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream, lookup_host};
+use tokio::time::{Instant, timeout, timeout_at};
+use tracing::{info, warn};
+
+use crate::consts::*;
+use crate::protocol::split_host_port;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// 轮询
+    RoundRobin,
+    /// 当前连接数最少
+    LeastConn,
+    /// 按目标地址哈希，同一目标固定走同一出口
+    Hash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamKind {
+    Bind(IpAddr),
+    Socks5(String),
+}
+
+impl FromStr for UpstreamKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(ip) = s.strip_prefix("bind:") {
+            let ip = ip
+                .parse()
+                .map_err(|e| format!("invalid bind ip {}: {}", ip, e))?;
+            Ok(UpstreamKind::Bind(ip))
+        } else if let Some(addr) = s.strip_prefix("socks5://") {
+            match addr.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(UpstreamKind::Socks5(addr.to_string()))
+                }
+                _ => Err(format!("invalid socks5 upstream: {}", s)),
+            }
+        } else {
+            Err(format!(
+                "invalid upstream (expect bind:IP or socks5://HOST:PORT): {}",
+                s
+            ))
+        }
+    }
+}
+
+impl fmt::Display for UpstreamKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamKind::Bind(ip) => write!(f, "bind:{}", ip),
+            UpstreamKind::Socks5(addr) => write!(f, "socks5://{}", addr),
+        }
+    }
+}
+
+impl Serialize for UpstreamKind {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub kind: UpstreamKind,
+    healthy: AtomicBool,
+    active: AtomicUsize,
+}
+
+// 使用中的上游，drop 时归还连接计数
+pub struct UpstreamLease {
+    upstream: Arc<Upstream>,
+}
+
+impl UpstreamLease {
+    pub fn name(&self) -> String {
+        self.upstream.kind.to_string()
+    }
+}
+
+impl Drop for UpstreamLease {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<Arc<Upstream>>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn new(kinds: Vec<UpstreamKind>, strategy: Strategy) -> Self {
+        let upstreams = kinds
+            .into_iter()
+            .map(|kind| {
+                Arc::new(Upstream {
+                    kind,
+                    healthy: AtomicBool::new(true),
+                    active: AtomicUsize::new(0),
+                })
+            })
+            .collect();
+
+        UpstreamPool {
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// 按策略排好尝试顺序，健康的上游排在前面
+    fn candidates(&self, target: &str) -> Vec<Arc<Upstream>> {
+        let n = self.upstreams.len();
+        let mut order: Vec<Arc<Upstream>> = match self.strategy {
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % n;
+                self.upstreams
+                    .iter()
+                    .cycle()
+                    .skip(start)
+                    .take(n)
+                    .cloned()
+                    .collect()
+            }
+            Strategy::Hash => {
+                let mut hasher = DefaultHasher::new();
+                target.hash(&mut hasher);
+                let start = (hasher.finish() % n as u64) as usize;
+                self.upstreams
+                    .iter()
+                    .cycle()
+                    .skip(start)
+                    .take(n)
+                    .cloned()
+                    .collect()
+            }
+            Strategy::LeastConn => {
+                let mut order = self.upstreams.clone();
+                order.sort_by_key(|u| u.active.load(Ordering::Relaxed));
+                order
+            }
+        };
+        // 稳定排序，不打乱策略给出的顺序
+        order.sort_by_key(|u| !u.healthy.load(Ordering::Relaxed));
+        order
+    }
+
+    /// 依次尝试上游，失败自动切换到下一个，全部失败时返回最后一个错误。
+    /// deadline 是整个切换过程的期限，超时返回 TimedOut
+    pub async fn connect(
+        &self,
+        target: &str,
+        deadline: Instant,
+    ) -> io::Result<(TcpStream, UpstreamLease)> {
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no upstream configured");
+
+        for upstream in self.candidates(target) {
+            upstream.active.fetch_add(1, Ordering::Relaxed);
+            let lease = UpstreamLease {
+                upstream: upstream.clone(),
+            };
+
+            // 超时算上游的问题：上游不可达或上游连不上目标时都表现为无响应
+            let result = match timeout_at(deadline, connect_via(&upstream.kind, target)).await {
+                Ok(result) => result,
+                Err(_) => Err(Failure::Upstream(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "连接目标超时",
+                ))),
+            };
+
+            let e = match result {
+                Ok(stream) => return Ok((stream, lease)),
+                // 只有上游本身的问题才摘除，目标拒绝连接等不算
+                Err(Failure::Upstream(e)) => {
+                    upstream.healthy.store(false, Ordering::Relaxed);
+                    e
+                }
+                Err(Failure::Target(e)) => e,
+            };
+            warn!("上游 {} 连接 {} 失败: {}", upstream.kind, target, e);
+            last_err = e;
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "连接目标超时"));
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// 定期探测上游，恢复或摘除
+    pub async fn health_check(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            for upstream in &self.upstreams {
+                let healthy = probe(&upstream.kind, interval).await;
+                let was = upstream.healthy.swap(healthy, Ordering::Relaxed);
+                if was != healthy {
+                    info!("上游 {} 健康状态: {}", upstream.kind, healthy);
+                }
+            }
+        }
+    }
+}
+
+// 区分上游故障和目标故障
+enum Failure {
+    Upstream(io::Error),
+    Target(io::Error),
+}
+
+async fn probe(kind: &UpstreamKind, probe_timeout: Duration) -> bool {
+    match kind {
+        UpstreamKind::Bind(ip) => bind_socket(*ip).is_ok(),
+        UpstreamKind::Socks5(addr) => matches!(
+            timeout(probe_timeout, TcpStream::connect(addr.as_str())).await,
+            Ok(Ok(_))
+        ),
+    }
+}
+
+fn bind_socket(ip: IpAddr) -> io::Result<TcpSocket> {
+    let socket = match ip {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(ip, 0))?;
+    Ok(socket)
+}
+
+async fn connect_via(kind: &UpstreamKind, target: &str) -> Result<TcpStream, Failure> {
+    match kind {
+        UpstreamKind::Bind(ip) => {
+            let socket = bind_socket(*ip).map_err(Failure::Upstream)?;
+            // 只能连接与源地址同协议族的目标地址
+            let addr = lookup_host(target)
+                .await
+                .map_err(Failure::Target)?
+                .find(|addr| addr.is_ipv4() == ip.is_ipv4())
+                .ok_or_else(|| {
+                    Failure::Target(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "目标没有匹配源地址协议族的 IP",
+                    ))
+                })?;
+            socket.connect(addr).await.map_err(Failure::Target)
+        }
+        UpstreamKind::Socks5(proxy) => socks5_connect(proxy, target).await,
+    }
+}
+
+// 作为客户端通过上游 SOCKS5 (免认证) 建立 CONNECT 隧道
+async fn socks5_connect(proxy: &str, target: &str) -> Result<TcpStream, Failure> {
+    let (host, port) = split_host_port(target).ok_or_else(|| {
+        Failure::Target(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid target",
+        ))
+    })?;
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| {
+                Failure::Target(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "domain too long",
+                ))
+            })?;
+            request.push(ATYP_DOMAIN);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+
+    let mut stream = TcpStream::connect(proxy).await.map_err(Failure::Upstream)?;
+    let head = socks5_handshake(&mut stream, &request)
+        .await
+        .map_err(Failure::Upstream)?;
+
+    if head[1] != REP_SUCCESS {
+        // 目标不可达由上游如实返回，不是上游本身的故障
+        let kind = match head[1] {
+            REP_CONNECTION_REFUSED => io::ErrorKind::ConnectionRefused,
+            REP_CONNECTION_NOT_ALLOWED => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        let msg = format!("上游代理返回 0x{:02x}", head[1]);
+        return Err(Failure::Target(io::Error::new(kind, msg)));
+    }
+
+    // 丢弃 BND.ADDR / BND.PORT
+    let addr_len = match head[3] {
+        ATYP_IPV4 => Ipv4Addr::UNSPECIFIED.octets().len(),
+        ATYP_IPV6 => Ipv6Addr::UNSPECIFIED.octets().len(),
+        ATYP_DOMAIN => stream.read_u8().await.map_err(Failure::Upstream)? as usize,
+        atyp => {
+            let msg = format!("unknow address type: 0x{:02x}", atyp);
+            return Err(Failure::Upstream(io::Error::other(msg)));
+        }
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream
+        .read_exact(&mut bound)
+        .await
+        .map_err(Failure::Upstream)?;
+
+    Ok(stream)
+}
+
+// 方法协商并发送 CONNECT 请求，返回应答头 [VER, REP, RSV, ATYP]
+async fn socks5_handshake(stream: &mut TcpStream, request: &[u8]) -> io::Result<[u8; 4]> {
+    stream
+        .write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH])
+        .await?;
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    if buf != [SOCKS_VERSION, METHOD_NO_AUTH] {
+        return Err(io::Error::other("上游代理不支持免认证"));
+    }
+
+    stream.write_all(request).await?;
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    Ok(head)
+}